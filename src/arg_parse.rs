@@ -1,45 +1,200 @@
 //! To parse the arguments of the command line
-use clap::Parser;
+use std::{ffi::OsStr, fs, path::Path};
+
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+
+use crate::{
+    cargo_command::{init_cargo_options, CargoOptions},
+    cargo_parse::{init_resolve_backend, ResolveBackend},
+    config::{init_config, load_config, project_config_path, Config, ConflictPolicy},
+    logger::{init_logger, ColorChoice},
+    manifest::PatchLocation,
+    monorepo::discover_projects,
+    output::{init_output, OutputFormat},
+    provenance::check_date,
+    report::ReportFormat,
+};
 
 /// A simple tool to patch cargo dependencies by command line
 #[derive(Parser, Debug)]
 #[command(about, long_about = None)]
 pub struct Args {
-    /// The path of the cargo project, where the Cargo.toml file is in
-    #[arg(short, long)]
-    pub cargo_path: Option<String>,
+    #[command(subcommand)]
+    pub command: Command,
 
-    /// The name of the package to be patched, which may be renamed
-    #[arg(short = 'n', long = "name")]
-    pub package_name: String,
+    /// The path of the cargo project, where the Cargo.toml file is in.
+    /// It can be given several times for `add` to patch several projects
+    #[arg(short, long, global = true)]
+    pub cargo_path: Vec<String>,
+
+    /// The path of the Cargo.toml file, following the convention of cargo
+    #[arg(long, global = true, conflicts_with = "cargo_path")]
+    pub manifest_path: Option<String>,
+
+    /// Patch every cargo project found under the directory by `add`, skipping the `target`
+    /// directories and the workspace members
+    #[arg(
+        long,
+        global = true,
+        value_name = "DIR",
+        conflicts_with_all = ["cargo_path", "manifest_path"]
+    )]
+    pub recursive: Option<String>,
+
+    /// The output format, where `json` emits one structured document per run
+    #[arg(long, value_enum, global = true, default_value_t = OutputFormat::Human)]
+    pub format: OutputFormat,
+
+    /// Only show the errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
 
-    /// The type of the patch, which can be `git`, `registry` or `path`
+    /// Show more details, where `-v` shows the lockfile lookups and the resolved sources,
+    /// and `-vv` also shows the cargo commands run
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// When to colour the log lines, where `auto` honours `NO_COLOR` and whether stderr is a terminal
+    #[arg(long, value_enum, global = true, default_value_t = ColorChoice::Auto)]
+    pub color: ColorChoice,
+
+    /// Run cargo without accessing the network
+    #[arg(long, global = true)]
+    pub offline: bool,
+
+    /// Require Cargo.lock and the cache are up to date, which implies `--offline`
+    #[arg(long, global = true)]
+    pub frozen: bool,
+
+    /// The toolchain used to run cargo, which can also be given as `+toolchain` before all the arguments
+    #[arg(long, global = true)]
+    pub toolchain: Option<String>,
+
+    /// The backend to resolve the packages of the project
     ///
-    /// - `git`: Patch the package to a git repository
+    /// - `lockfile`: Parse the Cargo.lock file directly
     ///
-    /// - `registry`: Patch the package to a registry
+    /// - `metadata`: Use `cargo metadata` to get the full resolve graph, which falls back to
+    ///   the Cargo.lock file if cargo isn't available
+    #[arg(long, value_enum, global = true, default_value_t = ResolveBackend::Lockfile)]
+    pub backend: ResolveBackend,
+}
+
+/// The commands of the tool
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Add a patch for the package
+    Add(Box<AddArgs>),
+    /// Remove the patch of the package
+    Remove(RemoveArgs),
+    /// List the patches in the Cargo.toml
+    List,
+    /// Show whether each patch is used by the Cargo.lock
+    Status,
+    /// Show who depends on the package from each workspace member
+    Why(WhyArgs),
+    /// Undo the newest patch operations recorded in the history
+    Undo(UndoArgs),
+    /// Show the patch operations recorded by `add` and `remove`
+    History,
+    /// Check whether the patches have expired or may be replaced by an upstream release
+    Check,
+    /// Check the patches against the policy file, such as in CI
+    Lint(LintArgs),
+    /// Run a cargo command with the patches passed by `--config`, without editing any file
+    Exec(ExecArgs),
+    /// Apply a patch set around a command, and restore the Cargo.toml and the Cargo.lock afterwards
+    With(WithArgs),
+    /// Enable or disable the patch profiles in the project config
+    #[command(subcommand)]
+    Profile(ProfileCommand),
+    /// Sync the patches from a shared patch file, or check whether they have drifted
+    Sync(SyncArgs),
+    /// Migrate the legacy `[replace]` table and the `paths` overrides of the cargo config into patches
+    Migrate,
+}
+
+impl Command {
+    /// The name of the command
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Add(_) => "add",
+            Command::Remove(_) => "remove",
+            Command::List => "list",
+            Command::Status => "status",
+            Command::Why(_) => "why",
+            Command::Undo(_) => "undo",
+            Command::History => "history",
+            Command::Check => "check",
+            Command::Lint(_) => "lint",
+            Command::Exec(_) => "exec",
+            Command::With(_) => "with",
+            Command::Profile(_) => "profile",
+            Command::Sync(_) => "sync",
+            Command::Migrate => "migrate",
+        }
+    }
+}
+
+/// The type of the patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PatchType {
+    /// Patch the package to a git repository
+    Git,
+    /// Patch the package to a registry
+    Registry,
+    /// Patch the package to a local path
+    Path,
+}
+
+/// The arguments of the `add` command
+#[derive(clap::Args, Debug)]
+pub struct AddArgs {
+    /// The patch specs, such as `log=gh:rust-lang/log#tag=v0.4.22`, `log=../log`,
+    /// `log=git+https://host/x.git#rev=abc` or `log@0.4.21`
     ///
-    /// - `path`: Patch the package to a local path
+    /// The relative local paths are relative to the cargo project, also when several projects
+    /// are patched.
+    pub specs: Vec<String>,
+
+    /// The file of the patch specs, one spec per line, where the empty lines and
+    /// the lines starting with `#` are skipped
+    #[arg(long, value_name = "FILE")]
+    pub batch: Option<String>,
+
+    /// The name of the package to be patched, which may be renamed
+    #[arg(
+        short = 'n',
+        long = "name",
+        required_unless_present_any = ["specs", "batch"],
+        requires = "patch_type"
+    )]
+    pub package_name: Option<String>,
+
+    /// The type of the patch
     ///
     /// Notes:
     ///
-    ///     - The `registry` only supports `crates-io`
+    /// - The `registry` points to the registry given by `--registry`
     ///
-    ///     - The `git` points to `github.com` defaultly
-    #[arg(short = 't', long = "type")]
-    pub patch_type: String,
+    /// - The `git` points to `github.com` defaultly
+    #[arg(short = 't', long = "type", value_enum, requires = "package_name")]
+    pub patch_type: Option<PatchType>,
 
     /// The real package name to be patched
+    ///
+    /// If not given, it is detected from the `package` field of the dependency in the manifests
     #[arg(short = 'r', long = "real-package-name")]
     pub real_package_name: Option<String>,
 
     /// The version requirement for the target patch
-    #[arg(short = 'v', long = "version")]
+    #[arg(long = "version")]
     pub package_version: Option<String>,
 
     /// The name of git repository to be patched for git patch
     ///
-    /// The format should be like `owner/repo`
+    /// The format should be like `owner/repo`, `alias:owner/repo` with a host alias such as `gh` or `gl`,
+    /// or a full git url such as `https://host/x.git`
     #[arg(long)]
     pub git_repo: Option<String>,
 
@@ -55,28 +210,223 @@ pub struct Args {
     #[arg(long)]
     pub tag: Option<String>,
 
-    /// The local path to be patched for path patch
+    /// The registry to be patched to for registry patch, which is configured in `[registries]`
+    /// of the cargo config and must differ from the source of the package
+    #[arg(long)]
+    pub registry: Option<String>,
+
+    /// Pick the newest version in the cached registry index matching the version requirement
+    /// for registry patch, where the requirement is `*` if `--version` is not given
+    #[arg(long)]
+    pub latest_matching: bool,
+
+    /// The local path to be patched for path patch, which is relative to the cargo project if it
+    /// is relative
     #[arg(long)]
     pub patch_path: Option<String>,
+
+    /// Patch all the packages of the same family together
+    ///
+    /// The family is all the packages which come from the same git source, or whose `repository`
+    /// metadata points to the same repository for registry packages.
+    ///
+    /// - For git patch, all of them are patched to the same git repository.
+    ///
+    /// - For path patch, each of them is patched to the crate with the same name inside the given path.
+    #[arg(long)]
+    pub family: bool,
+
+    /// What to do if the package has been patched, which overrides `on-conflict` in the config
+    #[arg(long, value_enum)]
+    pub on_conflict: Option<ConflictPolicy>,
+
+    /// Where to write the patches, which overrides `patch-location` in the config
+    #[arg(long, value_enum)]
+    pub location: Option<PatchLocation>,
+
+    /// The host alias used for the git repo in the format of `owner/repo`,
+    /// which overrides `git-host` in the config
+    #[arg(long)]
+    pub git_host: Option<String>,
+
+    /// The owner used for the git repo given only by its name, which overrides `fork-owner` in the config
+    #[arg(long)]
+    pub fork_owner: Option<String>,
+
+    /// Why the patch exists, which is recorded in the metadata of the Cargo.toml with the author and the date
+    #[arg(long)]
+    pub reason: Option<String>,
+
+    /// The issue tracking the patch, which is recorded in the metadata of the Cargo.toml
+    #[arg(long)]
+    pub issue: Option<String>,
+
+    /// The date after which the patch should be revisited, in the format of `YYYY-MM-DD`
+    #[arg(long)]
+    pub expires: Option<String>,
+
+    /// Also write the reason, the issue and the expiry date as a TOML comment above the patch
+    #[arg(long)]
+    pub comment: bool,
+
+    /// Report what changes in the Cargo.lock file after the patch is applied
+    ///
+    /// The Cargo.lock file is updated by `cargo update --workspace` after patching.
+    #[arg(long)]
+    pub report: bool,
+
+    /// The format of the report
+    #[arg(long, value_enum, default_value_t = ReportFormat::Human)]
+    pub report_format: ReportFormat,
 }
 
-/// To parse the arguments of the command line
-///
-/// If arguments are not valid, for example, the type is set as `git` but the git repo is not provided,
-/// then return None.
-///
-/// # Return
-//
-/// - Some(args): The parsed arguments
+/// The arguments of the `remove` command
+#[derive(clap::Args, Debug)]
+pub struct RemoveArgs {
+    /// The key of the patch or the real name of the patched package
+    #[arg(short = 'n', long = "name")]
+    pub package_name: String,
+
+    /// The source of the patch to remove, such as `crates-io` or a git url,
+    /// which is required if the package is patched for several sources
+    #[arg(long)]
+    pub source: Option<String>,
+}
+
+/// The arguments of the `why` command
+#[derive(clap::Args, Debug)]
+pub struct WhyArgs {
+    /// The name of the package
+    pub name: String,
+
+    /// The version the proposed patch would provide, to mark the requirements it doesn't satisfy
+    #[arg(long)]
+    pub version: Option<String>,
+
+    /// The local path the proposed patch would point to, whose version is read from its Cargo.toml
+    #[arg(long, conflicts_with = "version")]
+    pub patch_path: Option<String>,
+}
+
+/// The arguments of the `undo` command
+#[derive(clap::Args, Debug)]
+pub struct UndoArgs {
+    /// The number of the newest operations to undo
+    #[arg(default_value_t = 1)]
+    pub count: usize,
+}
+
+/// The arguments of the `lint` command
+#[derive(clap::Args, Debug)]
+pub struct LintArgs {
+    /// The policy file, which is `.dependencies-patch-policy.toml` in the cargo project defaultly
+    #[arg(long, value_name = "FILE")]
+    pub policy: Option<String>,
+}
+
+/// The arguments of the `exec` command
+#[derive(clap::Args, Debug)]
+pub struct ExecArgs {
+    /// The patch specs, such as `log=../log` or `log=gh:rust-lang/log#tag=v0.4.22`
+    #[arg(required_unless_present = "batch")]
+    pub specs: Vec<String>,
+
+    /// The file of the patch specs, one spec per line
+    #[arg(long, value_name = "FILE")]
+    pub batch: Option<String>,
+
+    /// The cargo command to run after `--`, such as `cargo test`
+    #[arg(last = true, required = true)]
+    pub command: Vec<String>,
+}
+
+/// The subcommands of the `profile` command
+#[derive(Subcommand, Debug)]
+pub enum ProfileCommand {
+    /// Apply all the patches of the profile
+    Enable {
+        /// The name of the profile
+        name: String,
+    },
+    /// Remove all the patches of the profile, keeping the other patches
+    Disable {
+        /// The name of the profile
+        name: String,
+    },
+    /// List the profiles and whether they are enabled
+    List,
+}
+
+/// The arguments of the `with` command
+#[derive(clap::Args, Debug)]
+pub struct WithArgs {
+    /// The patch set, which has the `[patch.<source>.<key>]` tables as in the Cargo.toml
+    #[arg(long, value_name = "FILE")]
+    pub file: String,
+
+    /// The command to run after `--`, such as `cargo test`
+    #[arg(last = true, required = true)]
+    pub command: Vec<String>,
+}
+
+/// The arguments of the `sync` command
+#[derive(clap::Args, Debug)]
+pub struct SyncArgs {
+    /// The shared patch file, which has the `[patch.<source>.<key>]` tables as in the Cargo.toml
+    #[arg(long, value_name = "FILE")]
+    pub from: String,
+
+    /// The stable id of the shared file recorded by the synced patches, which defaults to the
+    /// path of the file relative to the project and is required for a file outside the repository
+    #[arg(long)]
+    pub id: Option<String>,
+
+    /// Only list the differences, and fail if there is any, such as in CI
+    #[arg(long)]
+    pub check: bool,
+}
+
+/// To check the arguments of the `add` command
 ///
-/// - None: The arguments are not valid
-pub(crate) fn parse_args() -> Option<Args> {
-    let mut args = Args::parse();
-    match args.patch_type.as_str() {
-        "git" => {
+/// For example, the type is set as `git` but the git repo is not provided.
+/// The patch specs are checked when they are parsed.
+fn check_add_args(args: &AddArgs) -> bool {
+    if args
+        .expires
+        .as_ref()
+        .is_some_and(|expires| !check_date(expires))
+    {
+        error_log!(
+            code = "invalid-arguments",
+            "The expiry date should be in the format of `YYYY-MM-DD`!"
+        );
+        return false;
+    }
+    if args.comment && args.reason.is_none() && args.issue.is_none() && args.expires.is_none() {
+        error_log!(
+            code = "invalid-arguments",
+            "The comment requires the reason, the issue or the expiry date!"
+        );
+        return false;
+    }
+    let Some(patch_type) = args.patch_type else {
+        return true;
+    };
+    if args.registry.is_some() && patch_type != PatchType::Registry {
+        error_log!(
+            code = "invalid-arguments",
+            "The registry is only used for registry patch!"
+        );
+        return false;
+    }
+    match patch_type {
+        PatchType::Git => {
             if args.git_repo.is_none() {
-                error_log!("The git repo is required for git patch!");
-                return None;
+                error_log!(
+                    code = "invalid-arguments",
+                    "The git repo is required for git patch!"
+                );
+                return false;
             }
 
             // commit, branch and tag can't be used with each other
@@ -86,27 +436,170 @@ pub(crate) fn parse_args() -> Option<Args> {
                 args.tag.as_ref(),
             ];
             if judge_array.iter().filter(|x| x.is_some()).count() > 1 {
-                error_log!("The commit, branch and tag can't be used with each other!");
-                return None;
+                error_log!(
+                    code = "invalid-arguments",
+                    "The commit, branch and tag can't be used with each other!"
+                );
+                return false;
             }
         }
-        "path" => {
+        PatchType::Path => {
             if args.patch_path.is_none() {
-                error_log!("The path is required for path patch!");
-                return None;
+                error_log!(
+                    code = "invalid-arguments",
+                    "The path is required for path patch!"
+                );
+                return false;
+            }
+        }
+        PatchType::Registry => {
+            if args.package_version.is_none() && !args.latest_matching {
+                error_log!(
+                    code = "invalid-arguments",
+                    "The version is required for registry patch!"
+                );
+                return false;
+            }
+            if args.family {
+                error_log!(
+                    code = "invalid-arguments",
+                    "The family can't be used with registry patch!"
+                );
+                return false;
             }
         }
-        "registry" => {
-            if args.package_version.is_none() {
-                error_log!("The version is required for registry patch!");
+    }
+    true
+}
+
+/// To parse the arguments of the command line
+///
+/// If arguments are not valid, for example, the type is set as `git` but the git repo is not provided,
+/// then return None.
+///
+/// # Arguments
+///
+/// - `raw_args`: The arguments of the command line, including the binary name
+///
+/// # Return
+//
+/// - Some(args): The parsed arguments, where at least one `cargo_path` is always set
+///
+/// - None: The arguments are not valid
+pub(crate) fn parse_args(mut raw_args: Vec<String>) -> Option<Args> {
+    // Support the `+toolchain` selector like cargo, which must be the first argument
+    let toolchain = match raw_args.get(1) {
+        Some(arg) if arg.starts_with('+') => Some(raw_args.remove(1)[1..].to_string()),
+        _ => None,
+    };
+    let mut args = Args::parse_from(raw_args);
+    init_logger(args.quiet, args.verbose, args.color);
+    init_output(args.format, args.command.name());
+    if toolchain.is_some() {
+        if args.toolchain.is_some() {
+            error_log!(
+                code = "invalid-arguments",
+                "The toolchain can't be given by both `+toolchain` and `--toolchain`!"
+            );
+            return None;
+        }
+        args.toolchain = toolchain;
+    }
+    if let Command::Add(add_args) = &args.command {
+        if !check_add_args(add_args) {
+            return None;
+        }
+    }
+    // The project is where the Cargo.toml given by `--manifest-path` is in
+    if let Some(manifest_path) = &args.manifest_path {
+        let manifest_path = Path::new(manifest_path);
+        if manifest_path.file_name() != Some(OsStr::new("Cargo.toml")) {
+            error_log!(
+                code = "invalid-arguments",
+                "The manifest path must be a path to a Cargo.toml file!"
+            );
+            return None;
+        }
+        let cargo_path = match manifest_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().to_string(),
+            _ => ".".to_string(),
+        };
+        args.cargo_path = vec![cargo_path];
+    }
+    if let Some(dir) = &args.recursive {
+        args.cargo_path = match discover_projects(dir) {
+            Ok(projects) if !projects.is_empty() => projects,
+            Ok(_) => {
+                error_log!(
+                    code = "project-not-found",
+                    "No cargo project is found under {}",
+                    dir
+                );
                 return None;
             }
+            Err(mes) => {
+                error_log!(code = "project-not-found", "{}", mes);
+                return None;
+            }
+        };
+    }
+    if args.cargo_path.is_empty() {
+        args.cargo_path.push(".".to_string());
+    }
+    if args.cargo_path.len() > 1 && !matches!(args.command, Command::Add(_)) {
+        error_log!(
+            code = "invalid-arguments",
+            "Only `add` can be applied to several projects!"
+        );
+        return None;
+    }
+    // The config of the recursive run is loaded from the directory searched
+    let config_path = args.recursive.as_ref().unwrap_or(&args.cargo_path[0]);
+    let mut config = match load_config(config_path) {
+        Ok(config) => config,
+        Err(mes) => {
+            error_log!(code = "config-failed", "{}", mes);
+            return None;
+        }
+    };
+    // The specs are parsed once with the config, so it is shared by all the projects
+    let used_config = fs::canonicalize(project_config_path(config_path)).ok();
+    for cargo_path in &args.cargo_path {
+        let project_config = project_config_path(cargo_path);
+        if project_config.exists() && fs::canonicalize(&project_config).ok() != used_config {
+            warn_log!(
+                "The config {} is ignored, the config of {} is used for all the projects",
+                project_config.display(),
+                config_path
+            );
         }
-        _ => {
-            error_log!("Unsupported patch type: {}", args.patch_type);
+    }
+    // The options given by the command line win over the config
+    if let Command::Add(add_args) = &args.command {
+        config.merge(Config {
+            git_host: add_args.git_host.clone(),
+            fork_owner: add_args.fork_owner.clone(),
+            on_conflict: add_args.on_conflict,
+            patch_location: add_args.location,
+            ..Default::default()
+        });
+    }
+    if let Some(git_host) = &config.git_host {
+        if config.host(git_host).is_none() {
+            error_log!(
+                code = "config-failed",
+                "The git host {} is not a configured host alias!",
+                git_host
+            );
             return None;
         }
     }
-    args.cargo_path = Some(args.cargo_path.unwrap_or(".".to_string()));
+    init_config(config);
+    init_cargo_options(CargoOptions {
+        offline: args.offline,
+        frozen: args.frozen,
+        toolchain: args.toolchain.clone(),
+    });
+    init_resolve_backend(args.backend);
     Some(args)
 }
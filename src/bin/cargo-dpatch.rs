@@ -0,0 +1,13 @@
+//! The `cargo dpatch` subcommand, which is the same as `dependencies-patch`
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().collect::<Vec<_>>();
+    // Cargo runs `cargo-dpatch dpatch <args>` for `cargo dpatch <args>`
+    if args.get(1).map(String::as_str) == Some("dpatch") {
+        args.remove(1);
+    }
+    // Show `cargo dpatch` in the usage
+    args[0] = "cargo dpatch".to_string();
+    dependencies_patch::run(args)
+}
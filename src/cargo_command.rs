@@ -0,0 +1,153 @@
+//! To run cargo commands for the target project
+
+use std::{
+    ffi::OsString,
+    path::Path,
+    process::{Command, Output},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+/// The options passed to every cargo command
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CargoOptions {
+    /// Run cargo without accessing the network
+    pub offline: bool,
+    /// Require Cargo.lock and the cache are up to date
+    pub frozen: bool,
+    /// The toolchain used to run cargo, such as `nightly`
+    pub toolchain: Option<String>,
+}
+
+static CARGO_OPTIONS: OnceLock<CargoOptions> = OnceLock::new();
+
+/// Set the options for all the cargo commands, which can only be set once
+pub(crate) fn init_cargo_options(options: CargoOptions) {
+    let _ = CARGO_OPTIONS.set(options);
+}
+
+/// Get the cargo program to run
+///
+/// - If a toolchain is selected, the rustup proxy `cargo` in `PATH` is used to handle `+toolchain`.
+///
+/// - Otherwise, the `CARGO` environment variable is respected, which is set when running as a cargo
+///   subcommand.
+fn cargo_program(options: &CargoOptions) -> OsString {
+    if options.toolchain.is_none() {
+        if let Some(cargo) = std::env::var_os("CARGO") {
+            return cargo;
+        }
+    }
+    OsString::from("cargo")
+}
+
+/// Build a cargo command with the given arguments running in the target project
+pub(crate) fn cargo_command(cargo_path: &String, args: &[&str]) -> Command {
+    let options = CARGO_OPTIONS.get().cloned().unwrap_or_default();
+    let mut command = Command::new(cargo_program(&options));
+    if let Some(toolchain) = &options.toolchain {
+        command.arg(format!("+{}", toolchain));
+    }
+    command.args(args);
+    if options.offline {
+        command.arg("--offline");
+    }
+    if options.frozen {
+        command.arg("--frozen");
+    }
+    command.current_dir(Path::new(cargo_path));
+    command
+}
+
+/// Run a cargo command in the target project with its output captured
+///
+/// The output is only shown when the command fails.
+///
+/// # Return
+///
+/// - Ok(output): The output of the succeeded command
+///
+/// - Err(mes): The error message if cargo can't be run or the command fails
+pub(crate) fn run_cargo(cargo_path: &String, args: &[&str]) -> Result<Output, String> {
+    let mut command = cargo_command(cargo_path, args);
+    let command_line = format!("cargo {}", args.join(" "));
+    trace_log!("Run {:?}", command);
+    let output = command.output().map_err(|err| {
+        format!(
+            "Failed to run `{}`, please check whether cargo is installed or set the `CARGO` environment variable: {}",
+            command_line, err
+        )
+    })?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        return Err(format!(
+            "Failed to run `{}` in {}:\n{}{}",
+            command_line,
+            cargo_path,
+            stdout,
+            stderr.trim_end()
+        ));
+    }
+    trace_log!(
+        "The output of `{}`:\n{}",
+        command_line,
+        String::from_utf8_lossy(&output.stderr).trim_end()
+    );
+    Ok(output)
+}
+
+/// Whether Ctrl-C is pressed, which is also received by the running command
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Keep running on Ctrl-C, so the files changed for the user command can be restored after it
+pub(crate) fn defer_interrupts() {
+    if let Err(err) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::Relaxed)) {
+        warn_log!("Failed to handle Ctrl-C: {}", err);
+    }
+}
+
+/// Whether Ctrl-C is pressed since [`defer_interrupts`]
+pub(crate) fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Run the command given by the user in the target project, with its output shown
+///
+/// # Arguments
+///
+/// - `command`: The program and its arguments, such as `cargo test`
+///
+/// - `extra_args`: The arguments inserted after the program and its `+toolchain`, such as `--config`
+///
+/// # Return
+///
+/// - Ok(()): The command succeeds
+///
+/// - Err(mes): The error message if the command can't be run or it fails
+pub(crate) fn run_user_command(
+    cargo_path: &String,
+    command: &[String],
+    extra_args: &[String],
+) -> Result<(), String> {
+    let Some((program, rest)) = command.split_first() else {
+        return Err("No command is given".to_string());
+    };
+    let toolchains = rest.iter().take_while(|arg| arg.starts_with('+')).count();
+    let mut user_command = Command::new(program);
+    user_command
+        .args(&rest[..toolchains])
+        .args(extra_args)
+        .args(&rest[toolchains..])
+        .current_dir(Path::new(cargo_path));
+    debug_log!("Run {:?}", user_command);
+    let status = user_command
+        .status()
+        .map_err(|err| format!("Failed to run `{}`: {}", command.join(" "), err))?;
+    if !status.success() {
+        return Err(format!("`{}` exits with {}", command.join(" "), status));
+    }
+    Ok(())
+}
@@ -1,6 +1,17 @@
 //! To parse the cargo dependencies of the target project
 
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use clap::ValueEnum;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
+use toml::Table;
+
+use crate::{cargo_command::run_cargo, registry::registry_source_key};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) enum Dependency {
@@ -9,10 +20,53 @@ pub(crate) enum Dependency {
     Registry(String),
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// The backend to resolve the packages of the target project
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ResolveBackend {
+    /// Parse the Cargo.lock file directly
+    #[default]
+    Lockfile,
+    /// Use `cargo metadata`, which falls back to the Cargo.lock file if cargo isn't available
+    Metadata,
+}
+
+static RESOLVE_BACKEND: OnceLock<ResolveBackend> = OnceLock::new();
+
+/// The loaded resolve graphs of the projects
+static RESOLVE_CACHE: Mutex<BTreeMap<String, CargoResolve>> = Mutex::new(BTreeMap::new());
+
+/// Set the backend to resolve the packages, which can only be set once
+pub(crate) fn init_resolve_backend(backend: ResolveBackend) {
+    let _ = RESOLVE_BACKEND.set(backend);
+}
+
+/// A declared dependency of a package
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct PackageRequirement {
+    /// The real name of the required package
+    pub name: String,
+    /// The version requirement, such as `^0.4`
+    pub req: String,
+}
+
+/// A package in the resolve graph of the target project
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
 pub(crate) struct CargoPackage {
+    /// The unique id of the package in the resolve graph
+    pub id: String,
     pub name: String,
+    pub version: Option<String>,
     pub source: Option<String>,
+    /// Whether the package is a member of the workspace rather than a path dependency
+    pub workspace_member: bool,
+    /// The `repository` metadata, which is only known from `cargo metadata`
+    pub repository: Option<String>,
+    /// The enabled features, which are only known from `cargo metadata`
+    pub features: Vec<String>,
+    /// The ids of the packages it depends on
+    pub dependencies: Vec<String>,
+    /// The declared dependencies, which are only known from `cargo metadata`
+    pub requirements: Vec<PackageRequirement>,
 }
 
 impl CargoPackage {
@@ -33,9 +87,8 @@ impl CargoPackage {
                     } else {
                         Dependency::Git(git_url)
                     }
-                } else if source.starts_with("registry+") {
-                    // TODO: support more registries
-                    Dependency::Registry("crates-io".to_string())
+                } else if source.starts_with("registry+") || source.starts_with("sparse+") {
+                    Dependency::Registry(registry_source_key(source))
                 } else {
                     panic!("Unsupported source: {}", source)
                 }
@@ -45,15 +98,105 @@ impl CargoPackage {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+/// The resolve graph of the target project
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub(crate) struct CargoResolve {
+    pub packages: Vec<CargoPackage>,
+}
+
+impl CargoResolve {
+    /// Find the first package with the given name
+    pub fn find(&self, package_name: &str) -> Option<&CargoPackage> {
+        self.packages
+            .iter()
+            .find(|package| package.name == package_name)
+    }
+
+    /// Find the packages which depend on the package with the given id directly
+    pub fn dependents(&self, id: &str) -> Vec<&CargoPackage> {
+        self.packages
+            .iter()
+            .filter(|package| package.dependencies.iter().any(|dep| dep == id))
+            .collect()
+    }
+}
+
+/// A package in the Cargo.lock file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub(crate) struct LockPackage {
+    pub name: String,
+    pub version: Option<String>,
+    pub source: Option<String>,
+    /// The dependencies in the format of `name`, `name version` or `name version (source)`
+    #[serde(default)]
+    dependencies: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub(crate) struct CargoLock {
-    package: Vec<CargoPackage>,
+    #[serde(default)]
+    pub package: Vec<LockPackage>,
+    /// The patches recorded in the Cargo.lock
+    #[serde(default)]
+    pub patch: LockPatch,
 }
 
-pub(crate) fn pick_package(
-    cargo_path: &String,
-    package_name: &String,
-) -> Result<CargoPackage, String> {
+/// The `[patch]` section of the Cargo.lock
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub(crate) struct LockPatch {
+    /// The patches which are not used by any dependency
+    #[serde(default)]
+    pub unused: Vec<LockPackage>,
+}
+
+/// Read and parse the Cargo.lock file of the target project
+pub(crate) fn read_cargo_lock(cargo_path: &String) -> Result<CargoLock, String> {
+    let cargo_lock_path = format!("{}/Cargo.lock", cargo_path);
+    let cargo_lock = std::fs::read_to_string(&cargo_lock_path)
+        .map_err(|err| format!("Failed to read {}: {}", cargo_lock_path, err))?;
+    toml::from_str(&cargo_lock)
+        .map_err(|err| format!("Failed to parse {}: {}", cargo_lock_path, err))
+}
+
+/// The output of `cargo metadata --format-version 1`
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    packages: Vec<MetadataPackage>,
+    workspace_members: Vec<String>,
+    resolve: Option<MetadataResolve>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataPackage {
+    id: String,
+    name: String,
+    version: String,
+    source: Option<String>,
+    repository: Option<String>,
+    dependencies: Vec<MetadataDependency>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataDependency {
+    name: String,
+    req: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataResolve {
+    nodes: Vec<MetadataNode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataNode {
+    id: String,
+    dependencies: Vec<String>,
+    #[serde(default)]
+    features: Vec<String>,
+}
+
+/// Load the resolve graph of the target project by the selected backend
+pub(crate) fn load_resolve(cargo_path: &String) -> Result<CargoResolve, String> {
     // Check if the Cargo.toml file exists
     let cargo_toml_path = format!("{}/Cargo.toml", cargo_path);
     if !std::path::Path::new(&cargo_toml_path).exists() {
@@ -62,35 +205,714 @@ pub(crate) fn pick_package(
             cargo_path
         ));
     }
+    // The graph is loaded only once, so the patches written later don't affect the lookups
+    let mut cache = RESOLVE_CACHE.lock().unwrap();
+    if let Some(resolve) = cache.get(cargo_path) {
+        return Ok(resolve.clone());
+    }
+    let mut resolve = None;
+    if RESOLVE_BACKEND.get().copied().unwrap_or_default() == ResolveBackend::Metadata {
+        debug_log!("Resolve the packages of {} by cargo metadata", cargo_path);
+        match load_metadata(cargo_path) {
+            Ok(metadata) => resolve = Some(metadata),
+            Err(mes) => {
+                warn_log!("{}", mes);
+                warn_log!("Fall back to parse the Cargo.lock file");
+            }
+        }
+    }
+    let resolve = match resolve {
+        Some(resolve) => resolve,
+        None => {
+            debug_log!("Resolve the packages of {} by its Cargo.lock", cargo_path);
+            load_cargo_lock(cargo_path)?
+        }
+    };
+    debug_log!("{} packages are resolved", resolve.packages.len());
+    cache.insert(cargo_path.clone(), resolve.clone());
+    Ok(resolve)
+}
+
+/// Load the resolve graph by `cargo metadata`
+fn load_metadata(cargo_path: &String) -> Result<CargoResolve, String> {
+    let output = run_cargo(cargo_path, &["metadata", "--format-version", "1"])?;
+    let metadata: Metadata = serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("Failed to parse the output of cargo metadata: {}", err))?;
+    let nodes = metadata
+        .resolve
+        .map(|resolve| resolve.nodes)
+        .unwrap_or_default();
+    let packages = metadata
+        .packages
+        .into_iter()
+        .map(|package| {
+            let node = nodes.iter().find(|node| node.id == package.id);
+            CargoPackage {
+                workspace_member: metadata.workspace_members.contains(&package.id),
+                features: node.map(|node| node.features.clone()).unwrap_or_default(),
+                dependencies: node
+                    .map(|node| node.dependencies.clone())
+                    .unwrap_or_default(),
+                requirements: package
+                    .dependencies
+                    .into_iter()
+                    .map(|dependency| PackageRequirement {
+                        name: dependency.name,
+                        req: dependency.req,
+                    })
+                    .collect(),
+                id: package.id,
+                name: package.name,
+                version: Some(package.version),
+                source: package.source,
+                repository: package.repository,
+            }
+        })
+        .collect();
+    Ok(CargoResolve { packages })
+}
+
+/// Load the resolve graph from the Cargo.lock file, generating it if it doesn't exist
+fn load_cargo_lock(cargo_path: &String) -> Result<CargoResolve, String> {
     let cargo_lock_path = format!("{}/Cargo.lock", cargo_path);
     // Check if the Cargo.lock file exists
     if !std::path::Path::new(&cargo_lock_path).exists() {
         warn_log!("It will create a new Cargo.lock file");
         // Execute `cargo generate-lockfile` to generate the Cargo.lock file
-        let output = std::process::Command::new("cargo")
-            .arg("generate-lockfile")
-            .current_dir(cargo_path)
-            .spawn()
-            .expect("Failed to execute command cargo generate-lockfile")
-            .wait();
-        if output.is_err() || !output.unwrap().success() {
-            return Err(format!(
-                "Failed to read the Cargo.lock file in {}",
-                cargo_path
-            ));
-        }
+        run_cargo(cargo_path, &["generate-lockfile"])?;
     }
-    // Check if the package exists in the Cargo.lock file
-    let cargo_lock: CargoLock = toml::from_str(&std::fs::read_to_string(&cargo_lock_path).unwrap())
-        .expect("Failed to parse Cargo.lock file");
+    let cargo_lock = read_cargo_lock(cargo_path)?;
 
-    cargo_lock
+    // The lockfile can't tell workspace members from path dependencies, so check the manifests
+    let member_names = workspace_manifests(cargo_path)?
+        .into_iter()
+        .filter_map(|(_, manifest)| {
+            manifest
+                .get("package")
+                .and_then(|package| package.get("name"))
+                .and_then(|name| name.as_str().map(String::from))
+        })
+        .collect::<Vec<_>>();
+    let lock_id = |package: &LockPackage| {
+        format!(
+            "{} {}{}",
+            package.name,
+            package.version.clone().unwrap_or_default(),
+            package
+                .source
+                .as_ref()
+                .map(|source| format!(" ({})", source))
+                .unwrap_or_default()
+        )
+    };
+    let packages = cargo_lock
         .package
         .iter()
-        .find(|package| package.name == *package_name)
-        .cloned()
-        .ok_or(format!(
+        .map(|package| CargoPackage {
+            id: lock_id(package),
+            name: package.name.clone(),
+            version: package.version.clone(),
+            source: package.source.clone(),
+            workspace_member: package.source.is_none() && member_names.contains(&package.name),
+            dependencies: package
+                .dependencies
+                .iter()
+                .filter_map(|dependency| {
+                    // The version and source are only given when the name is ambiguous
+                    let mut parts = dependency.splitn(3, ' ');
+                    let name = parts.next()?;
+                    let version = parts.next();
+                    let source = parts
+                        .next()
+                        .map(|source| source.trim_start_matches('(').trim_end_matches(')'));
+                    cargo_lock
+                        .package
+                        .iter()
+                        .find(|target| {
+                            target.name == name
+                                && version.is_none_or(|version| {
+                                    target.version.as_deref() == Some(version)
+                                })
+                                && source
+                                    .is_none_or(|source| target.source.as_deref() == Some(source))
+                        })
+                        .map(lock_id)
+                })
+                .collect(),
+            ..Default::default()
+        })
+        .collect();
+    Ok(CargoResolve { packages })
+}
+
+/// Pick the package from the resolve graph of the target project
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project, where the Cargo.lock is in
+///
+/// - `package_name`: The real name of the package
+///
+/// - `version`: The version or the requirement of the patch, which picks the locked version
+///   compatible with it, and fails if no locked version is. Without it, the semver
+///   incompatible versions locked are an error, since the patch can't be told apart.
+pub(crate) fn pick_package(
+    cargo_path: &String,
+    package_name: &String,
+    version: Option<&str>,
+) -> Result<CargoPackage, String> {
+    // Check if the package exists in the resolve graph
+    let resolve = load_resolve(cargo_path)?;
+    let package = pick_locked(&resolve, package_name, version)?;
+    debug_log!(
+        "Found the package {} {} from {}",
+        package.name,
+        package.version.clone().unwrap_or_default(),
+        package.source.clone().unwrap_or("a local path".to_string())
+    );
+    Ok(package)
+}
+
+/// Pick the locked package compatible with the version of the patch, see [`pick_package`]
+fn pick_locked(
+    resolve: &CargoResolve,
+    package_name: &String,
+    version: Option<&str>,
+) -> Result<CargoPackage, String> {
+    let locked = resolve
+        .packages
+        .iter()
+        .filter(|package| package.name == *package_name)
+        .collect::<Vec<_>>();
+    let versions = locked
+        .iter()
+        .filter_map(|package| package.version.clone())
+        .collect::<Vec<_>>();
+    let Some(first) = locked.first() else {
+        return Err(format!(
             "The package {} is not found in the Cargo.lock file",
             package_name
-        ))
+        ));
+    };
+    // A requirement such as `*` has no range, which is the same as no version
+    let range = version.and_then(compat_range);
+    if let (Some(version), Some(range)) = (version, &range) {
+        let compatible = locked.iter().find(|package| {
+            package.version.as_deref().and_then(compat_range).as_ref() == Some(range)
+        });
+        return compatible.map(|package| (*package).clone()).ok_or(format!(
+            "No locked version of {} matches {}, the locked versions are: {}",
+            package_name,
+            version,
+            versions.join(", ")
+        ));
+    }
+    let ranges = versions
+        .iter()
+        .filter_map(|version| compat_range(version))
+        .collect::<BTreeSet<_>>();
+    if ranges.len() > 1 {
+        return Err(format!(
+            "Several incompatible versions of {} are locked: {}, give the version of the patch by `--version` or `#version=` in the spec",
+            package_name,
+            versions.join(", ")
+        ));
+    }
+    Ok((*first).clone())
+}
+
+/// Get the range of the semver compatible versions which the version or the requirement is in
+///
+/// The range is `1` for `1.2.3`, `07` for `0.7.5` and `003` for `0.0.3`, which is also the
+/// suffix of the patch keys for the several versions of a package, such as `rand07`.
+pub(crate) fn compat_range(version: &str) -> Option<String> {
+    let (major, minor, patch) = match Version::parse(version) {
+        Ok(version) => (version.major, version.minor, version.patch),
+        Err(_) => {
+            let req = VersionReq::parse(version).ok()?;
+            let comparator = req.comparators.first()?;
+            (
+                comparator.major,
+                comparator.minor.unwrap_or(0),
+                comparator.patch.unwrap_or(0),
+            )
+        }
+    };
+    Some(match (major, minor) {
+        (0, 0) => format!("00{}", patch),
+        (0, minor) => format!("0{}", minor),
+        (major, _) => major.to_string(),
+    })
+}
+
+/// Get the cargo home directory, which is `$CARGO_HOME` or `$HOME/.cargo` defaultly
+pub(crate) fn cargo_home() -> Option<PathBuf> {
+    if let Some(home) = std::env::var_os("CARGO_HOME") {
+        return Some(PathBuf::from(home));
+    }
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo"))
+}
+
+/// Normalize a repository url so that different spellings of the same repository are equal
+///
+/// e.g. `https://github.com/tokio-rs/tokio.git/` and `https://github.com/Tokio-rs/tokio` are the same.
+fn normalize_repository(repository: &str) -> String {
+    let mut repository = repository.trim().to_lowercase();
+    while repository.ends_with('/') {
+        repository.pop();
+    }
+    if let Some(stripped) = repository.strip_suffix(".git") {
+        repository = stripped.to_string();
+    }
+    repository
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_start_matches("git+")
+        .trim_start_matches("ssh://git@")
+        .to_string()
+}
+
+/// Get the `package.repository` field of a registry package
+///
+/// It is known from `cargo metadata`, otherwise it is read from the cargo source cache.
+///
+/// The sources are unpacked at `$CARGO_HOME/registry/src/<index>/<name>-<version>`.
+fn registry_repository(package: &CargoPackage) -> Option<String> {
+    if let Some(repository) = &package.repository {
+        return Some(normalize_repository(repository));
+    }
+    let version = package.version.as_ref()?;
+    let src_dir = cargo_home()?.join("registry").join("src");
+    for index in std::fs::read_dir(src_dir).ok()?.flatten() {
+        let manifest_path = index
+            .path()
+            .join(format!("{}-{}", package.name, version))
+            .join("Cargo.toml");
+        let Ok(manifest) = std::fs::read_to_string(manifest_path) else {
+            continue;
+        };
+        let Ok(manifest) = toml::from_str::<Table>(&manifest) else {
+            continue;
+        };
+        if let Some(repository) = manifest
+            .get("package")
+            .and_then(|package| package.get("repository"))
+            .and_then(|repository| repository.as_str())
+        {
+            return Some(normalize_repository(repository));
+        }
+    }
+    None
+}
+
+/// Find all the packages which belong to the same family as the given package
+///
+/// - For a git package, the family is all the packages which share its source.
+///
+/// - For a registry package, the family is all the registry packages whose `repository` metadata
+///   points to the same repository.
+///
+/// The given package itself is always the first one of the returned packages.
+pub(crate) fn pick_family(
+    cargo_path: &String,
+    package_name: &String,
+) -> Result<Vec<CargoPackage>, String> {
+    let resolve = load_resolve(cargo_path)?;
+    let package = resolve.find(package_name).cloned().ok_or(format!(
+        "The package {} is not found in the Cargo.lock file",
+        package_name
+    ))?;
+
+    let mut family = vec![package.clone()];
+    match package.parse_dependency() {
+        Dependency::Git(_) => {
+            family.extend(
+                resolve.packages.into_iter().filter(|member| {
+                    member.name != package.name && member.source == package.source
+                }),
+            );
+        }
+        Dependency::Registry(_) => {
+            let repository = registry_repository(&package).ok_or(format!(
+                "The repository of package {} is unknown, please fetch its source with `cargo fetch` first",
+                package_name
+            ))?;
+            for member in resolve.packages {
+                if member.name == package.name
+                    || family.iter().any(|known| known.name == member.name)
+                    || !matches!(member.parse_dependency(), Dependency::Registry(_))
+                {
+                    continue;
+                }
+                if registry_repository(&member).as_ref() == Some(&repository) {
+                    family.push(member);
+                }
+            }
+        }
+        Dependency::Path => {
+            return Err(format!(
+                "The package {} is a path dependency, which has no family",
+                package_name
+            ));
+        }
+    }
+    debug_log!(
+        "The family of {} is: {}",
+        package_name,
+        family
+            .iter()
+            .map(|member| member.name.clone())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(family)
+}
+
+/// The names of the dependency tables in a manifest
+const DEPENDENCY_TABLES: [&str; 3] = ["dependencies", "dev-dependencies", "build-dependencies"];
+
+/// Get the manifests of the target project, including the root one and all the workspace members
+///
+/// The members are read from `workspace.members`, where a `*` pattern in the last path component
+/// is supported, and `workspace.exclude` is respected.
+pub(crate) fn workspace_manifests(cargo_path: &String) -> Result<Vec<(PathBuf, Table)>, String> {
+    let root_path = Path::new(cargo_path).join("Cargo.toml");
+    let root = read_manifest(&root_path)?;
+    let mut manifests = vec![(root_path, root.clone())];
+
+    let Some(workspace) = root
+        .get("workspace")
+        .and_then(|workspace| workspace.as_table())
+    else {
+        return Ok(manifests);
+    };
+    let patterns = |key: &str| -> Vec<String> {
+        workspace
+            .get(key)
+            .and_then(|value| value.as_array())
+            .map(|array| {
+                array
+                    .iter()
+                    .filter_map(|item| {
+                        item.as_str()
+                            .map(|item| item.trim_end_matches('/').to_string())
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let excludes = patterns("exclude");
+    let mut members = Vec::new();
+    for member in patterns("members") {
+        match member.strip_suffix('*') {
+            Some(prefix) => {
+                // Only support the pattern like `crates/*` or `crates/foo-*`
+                let (dir, file_prefix) = match prefix.rfind('/') {
+                    Some(pos) => (&prefix[..pos], &prefix[pos + 1..]),
+                    None => ("", prefix),
+                };
+                let Ok(entries) = std::fs::read_dir(Path::new(cargo_path).join(dir)) else {
+                    continue;
+                };
+                let mut entries = entries
+                    .flatten()
+                    .map(|entry| entry.file_name().to_string_lossy().to_string())
+                    .filter(|name| name.starts_with(file_prefix))
+                    .map(|name| match dir {
+                        "" => name,
+                        dir => format!("{}/{}", dir, name),
+                    })
+                    .collect::<Vec<_>>();
+                entries.sort();
+                members.extend(entries);
+            }
+            None => members.push(member),
+        }
+    }
+    for member in members {
+        if excludes.contains(&member) {
+            continue;
+        }
+        let manifest_path = Path::new(cargo_path).join(&member).join("Cargo.toml");
+        if !manifest_path.exists() || manifests.iter().any(|(path, _)| *path == manifest_path) {
+            continue;
+        }
+        debug_log!("Found the workspace member {}", manifest_path.display());
+        let manifest = read_manifest(&manifest_path)?;
+        manifests.push((manifest_path, manifest));
+    }
+    Ok(manifests)
+}
+
+/// Read and parse a manifest file
+fn read_manifest(manifest_path: &Path) -> Result<Table, String> {
+    let manifest = std::fs::read_to_string(manifest_path)
+        .map_err(|err| format!("Failed to read {}: {}", manifest_path.display(), err))?;
+    toml::from_str(&manifest)
+        .map_err(|err| format!("Failed to parse {}: {}", manifest_path.display(), err))
+}
+
+/// Get all the dependency tables of a manifest
+///
+/// They are `[dependencies]`, `[dev-dependencies]`, `[build-dependencies]`, the ones under
+/// `[target.'cfg(..)']` and `[workspace.dependencies]`.
+fn dependency_tables(manifest: &Table) -> Vec<&Table> {
+    fn push_tables<'a>(table: &'a Table, tables: &mut Vec<&'a Table>) {
+        for name in DEPENDENCY_TABLES {
+            if let Some(dependencies) = table.get(name).and_then(|table| table.as_table()) {
+                tables.push(dependencies);
+            }
+        }
+    }
+
+    let mut tables = Vec::new();
+    push_tables(manifest, &mut tables);
+    if let Some(targets) = manifest.get("target").and_then(|target| target.as_table()) {
+        for target in targets.values().filter_map(|target| target.as_table()) {
+            push_tables(target, &mut tables);
+        }
+    }
+    if let Some(dependencies) = manifest
+        .get("workspace")
+        .and_then(|workspace| workspace.get("dependencies"))
+        .and_then(|dependencies| dependencies.as_table())
+    {
+        tables.push(dependencies);
+    }
+    tables
+}
+
+/// Resolve the real package name of a dependency, which may be renamed by the `package` field
+///
+/// All the dependency tables of all the workspace members are scanned.
+///
+/// # Return
+///
+/// - Ok(name): The real package name, which is the dependency name itself if it is not renamed
+///
+/// - Err(mes): The dependency refers to different packages in different places
+pub(crate) fn resolve_package_name(
+    cargo_path: &String,
+    dependency_name: &String,
+) -> Result<String, String> {
+    // The real package names and where they are declared
+    let mut packages: Vec<(String, PathBuf)> = Vec::new();
+    for (manifest_path, manifest) in workspace_manifests(cargo_path)? {
+        for dependencies in dependency_tables(&manifest) {
+            let Some(dependency) = dependencies.get(dependency_name) else {
+                continue;
+            };
+            let package = dependency
+                .get("package")
+                .and_then(|package| package.as_str())
+                .unwrap_or(dependency_name);
+            // `foo = { workspace = true }` inherits the package from `[workspace.dependencies]`
+            if dependency
+                .get("workspace")
+                .and_then(|value| value.as_bool())
+                == Some(true)
+                && dependency.get("package").is_none()
+            {
+                continue;
+            }
+            debug_log!(
+                "The dependency {} refers to the package {} in {}",
+                dependency_name,
+                package,
+                manifest_path.display()
+            );
+            if !packages.iter().any(|(name, _)| name == package) {
+                packages.push((package.to_string(), manifest_path.clone()));
+            }
+        }
+    }
+    match packages.len() {
+        0 => Ok(dependency_name.clone()),
+        1 => Ok(packages.remove(0).0),
+        _ => Err(format!(
+            "The dependency {} refers to different packages: {}. Please specify the real package name by `--real-package-name`",
+            dependency_name,
+            packages
+                .iter()
+                .map(|(name, path)| format!("{} (in {})", name, path.display()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Get the version requirement of a dependency if it is on the package,
+/// such as `foo = "0.4"` or `foo = { version = "0.4" }`
+fn dependency_requirement<'a>(
+    name: &'a str,
+    dependency: &'a toml::Value,
+    package_name: &str,
+) -> Option<&'a str> {
+    let package = dependency
+        .get("package")
+        .and_then(|package| package.as_str())
+        .unwrap_or(name);
+    if package != package_name {
+        return None;
+    }
+    dependency.as_str().or(dependency
+        .get("version")
+        .and_then(|version| version.as_str()))
+}
+
+/// Push the version requirements on the package declared in the dependency tables of the manifest
+///
+/// The dependencies inherited by `foo = { workspace = true }` are looked up in the
+/// `[workspace.dependencies]` of the root manifest.
+fn manifest_requirements(
+    manifest: &Table,
+    root: &Table,
+    package_name: &str,
+    requirements: &mut Vec<String>,
+) {
+    let inherited = root
+        .get("workspace")
+        .and_then(|workspace| workspace.get("dependencies"));
+    for dependencies in dependency_tables(manifest) {
+        for (name, dependency) in dependencies {
+            let dependency = match dependency
+                .get("workspace")
+                .and_then(|value| value.as_bool())
+            {
+                Some(true) => match inherited.and_then(|inherited| inherited.get(name)) {
+                    Some(dependency) => dependency,
+                    None => continue,
+                },
+                _ => dependency,
+            };
+            if let Some(req) = dependency_requirement(name, dependency, package_name)
+                .filter(|req| !requirements.iter().any(|known| known == req))
+            {
+                requirements.push(req.to_string());
+            }
+        }
+    }
+}
+
+/// Get the version requirements on the package declared by the other packages
+///
+/// The requirements of all the packages are known from `cargo metadata`, otherwise only the
+/// ones declared in the dependency tables of the workspace members are found.
+pub(crate) fn package_requirements(
+    cargo_path: &String,
+    package_name: &str,
+) -> Result<Vec<String>, String> {
+    let mut requirements = Vec::new();
+    let resolve = load_resolve(cargo_path)?;
+    for requirement in resolve
+        .packages
+        .iter()
+        .flat_map(|package| &package.requirements)
+        .filter(|requirement| requirement.name == package_name)
+    {
+        if !requirements.contains(&requirement.req) {
+            requirements.push(requirement.req.clone());
+        }
+    }
+    if !requirements.is_empty() {
+        return Ok(requirements);
+    }
+    let manifests = workspace_manifests(cargo_path)?;
+    for (_, manifest) in &manifests {
+        manifest_requirements(manifest, &manifests[0].1, package_name, &mut requirements);
+    }
+    Ok(requirements)
+}
+
+/// Get the version requirements on the package declared by a workspace member in its manifest,
+/// which are known without `cargo metadata`
+pub(crate) fn member_requirements(
+    cargo_path: &String,
+    member_name: &str,
+    package_name: &str,
+) -> Result<Vec<String>, String> {
+    let mut requirements = Vec::new();
+    let manifests = workspace_manifests(cargo_path)?;
+    for (_, manifest) in &manifests {
+        let name = manifest
+            .get("package")
+            .and_then(|package| package.get("name"))
+            .and_then(|name| name.as_str());
+        if name == Some(member_name) {
+            manifest_requirements(manifest, &manifests[0].1, package_name, &mut requirements);
+        }
+    }
+    Ok(requirements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compat_range_of_versions() {
+        for (version, range) in [
+            ("1.2.3", "1"),
+            ("2.0.0-alpha.1", "2"),
+            ("0.7.5", "07"),
+            ("0.10.0", "010"),
+            ("0.0.3", "003"),
+        ] {
+            assert_eq!(compat_range(version).as_deref(), Some(range), "{}", version);
+        }
+    }
+
+    #[test]
+    fn compat_range_of_requirements() {
+        for (req, range) in [
+            ("1", "1"),
+            ("^1.2", "1"),
+            ("~0.7", "07"),
+            ("=0.8.5", "08"),
+            ("0.0.3", "003"),
+            (">=0.4, <0.5", "04"),
+        ] {
+            assert_eq!(compat_range(req).as_deref(), Some(range), "{}", req);
+        }
+        assert_eq!(compat_range("*"), None);
+        assert_eq!(compat_range("not a version"), None);
+    }
+
+    #[test]
+    fn pick_locked_by_the_version_of_the_patch() {
+        let resolve = CargoResolve {
+            packages: [("rand", "0.7.3"), ("rand", "0.8.5"), ("strsim", "0.11.1")]
+                .map(|(name, version)| CargoPackage {
+                    name: name.to_string(),
+                    version: Some(version.to_string()),
+                    ..Default::default()
+                })
+                .to_vec(),
+        };
+        let pick = |name: &str, version| {
+            pick_locked(&resolve, &name.to_string(), version)
+                .map(|package| package.version.unwrap())
+        };
+
+        assert_eq!(pick("rand", Some("0.7.5")).unwrap(), "0.7.3");
+        assert_eq!(pick("rand", Some("^0.8")).unwrap(), "0.8.5");
+        assert_eq!(pick("strsim", None).unwrap(), "0.11.1");
+        assert_eq!(pick("strsim", Some("0.11.3")).unwrap(), "0.11.1");
+        // The requested version matches neither of the incompatible locked versions
+        assert_eq!(
+            pick("rand", Some("0.9.0")).unwrap_err(),
+            "No locked version of rand matches 0.9.0, the locked versions are: 0.7.3, 0.8.5"
+        );
+        assert_eq!(
+            pick("strsim", Some("0.12.0")).unwrap_err(),
+            "No locked version of strsim matches 0.12.0, the locked versions are: 0.11.1"
+        );
+        assert!(pick("rand", None)
+            .unwrap_err()
+            .starts_with("Several incompatible versions of rand are locked: 0.7.3, 0.8.5"));
+        assert_eq!(
+            pick("log", Some("0.4.0")).unwrap_err(),
+            "The package log is not found in the Cargo.lock file"
+        );
+    }
 }
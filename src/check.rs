@@ -0,0 +1,195 @@
+//! Check whether the patches have expired or may be obsolete
+//!
+//! A patch expires after the date given by `--expires`, or by an `# expires: YYYY-MM-DD`
+//! comment written above it. A registry package patched is likely obsolete once an upstream
+//! release newer than the locked version satisfies all the requirements on it.
+
+use semver::{Version, VersionReq};
+use serde::Serialize;
+
+use crate::{
+    cargo_parse::{package_requirements, read_cargo_lock},
+    index_cache::cached_versions,
+    list::check_status,
+    manifest::{read_patches, PatchEntry},
+    output::{is_json, record_data},
+    provenance::comment_expiry,
+    registry::{registry_index, CRATES_IO},
+    transaction::utc_now,
+};
+
+/// The result of checking a patch
+#[derive(Debug, Clone, Serialize)]
+struct PatchCheck {
+    #[serde(flatten)]
+    entry: PatchEntry,
+    /// The date after which the patch should be revisited
+    expires: Option<String>,
+    /// Whether the expiry date has passed
+    expired: bool,
+    /// The version locked from the patch
+    locked_version: Option<String>,
+    /// The newest upstream release which is newer than the locked version and satisfies the requirements
+    upstream_version: Option<String>,
+    /// The requirements on the patched package
+    requirements: Vec<String>,
+}
+
+/// Get the index url of the source patched, which is `None` for a git source
+fn source_index(cargo_path: &String, source: &str) -> Option<String> {
+    if source == CRATES_IO {
+        registry_index(cargo_path, CRATES_IO).ok()
+    } else if source.starts_with("sparse+") {
+        Some(source.to_string())
+    } else {
+        // A git url can't be told from the index url of a git registry
+        None
+    }
+}
+
+/// Find the newest upstream release which can replace the patch
+///
+/// # Arguments
+///
+/// - `entry`: The patch to be checked
+///
+/// - `locked_version`: The version locked from the patch
+///
+/// - `requirements`: The requirements on the patched package, which must all be satisfied
+///
+/// # Return
+///
+/// - Some(version): The newest non-yanked release in the cached index of the patched source
+/// - None: There is no such release, or it can't be known offline
+fn find_upstream(
+    cargo_path: &String,
+    entry: &PatchEntry,
+    locked_version: &str,
+    requirements: &[String],
+) -> Option<String> {
+    let index = source_index(cargo_path, &entry.source)?;
+    let locked_version = Version::parse(locked_version).ok()?;
+    let requirements = requirements
+        .iter()
+        .filter_map(|req| VersionReq::parse(req).ok())
+        .collect::<Vec<_>>();
+    if requirements.is_empty() {
+        debug_log!("The requirements on {} are unknown", entry.package);
+        return None;
+    }
+    let versions = match cached_versions(&index, &entry.package) {
+        Ok(versions) => versions,
+        Err(mes) => {
+            warn_log!("{}, the obsolescence is not checked", mes);
+            return None;
+        }
+    };
+    versions
+        .into_iter()
+        .rev()
+        .find(|version| {
+            !version.yanked
+                && version.version > locked_version
+                && requirements.iter().all(|req| req.matches(&version.version))
+        })
+        .map(|version| version.version.to_string())
+}
+
+/// Check every patch, where the expired patches are errors and the obsolete patches are warnings
+pub(crate) fn check(cargo_path: &String) {
+    let entries = match read_patches(cargo_path) {
+        Ok(entries) => entries,
+        Err(mes) => {
+            error_log!(code = "manifest-failed", "{}", mes);
+            return;
+        }
+    };
+    // The expiry can still be checked without the Cargo.lock
+    let cargo_lock = match read_cargo_lock(cargo_path) {
+        Ok(cargo_lock) => Some(cargo_lock),
+        Err(mes) => {
+            warn_log!("{}, the obsolescence is not checked", mes);
+            None
+        }
+    };
+    let today = &utc_now()[..10];
+    let mut checks = Vec::new();
+    for entry in entries {
+        let expires = entry
+            .provenance
+            .as_ref()
+            .and_then(|provenance| provenance.expires.clone())
+            .or_else(|| comment_expiry(cargo_path, &entry));
+        let expired = expires.as_deref().is_some_and(|expires| expires < today);
+        if let (true, Some(expires)) = (expired, &expires) {
+            error_log!(
+                code = "patch-expired",
+                "The patch {}.{} expired on {}",
+                entry.source,
+                entry.key,
+                expires
+            );
+        }
+        let locked_version = cargo_lock
+            .as_ref()
+            .and_then(|cargo_lock| check_status(&entry, cargo_lock).locked_version);
+        let requirements = match package_requirements(cargo_path, &entry.package) {
+            Ok(requirements) => requirements,
+            Err(mes) => {
+                warn_log!("{}", mes);
+                Vec::new()
+            }
+        };
+        let upstream_version = locked_version.as_ref().and_then(|locked_version| {
+            find_upstream(cargo_path, &entry, locked_version, &requirements)
+        });
+        if let (Some(upstream_version), Some(locked_version)) = (&upstream_version, &locked_version)
+        {
+            warn_log!(
+                "The upstream release {} {} is newer than the locked {} and satisfies {}, the patch {}.{} may be obsolete and replaced by a version bump",
+                entry.package,
+                upstream_version,
+                locked_version,
+                requirements.join(", "),
+                entry.source,
+                entry.key
+            );
+        }
+        checks.push(PatchCheck {
+            entry,
+            expires,
+            expired,
+            locked_version,
+            upstream_version,
+            requirements,
+        });
+    }
+    if is_json() {
+        record_data(&checks);
+        return;
+    }
+    if checks.is_empty() {
+        info_log!("There is no patch in the Cargo.toml");
+        return;
+    }
+    for check in &checks {
+        let mut states = Vec::new();
+        match (&check.expires, check.expired) {
+            (Some(expires), true) => states.push(format!("expired on {}", expires)),
+            (Some(expires), false) => states.push(format!("expires on {}", expires)),
+            (None, _) => {}
+        }
+        if let Some(upstream_version) = &check.upstream_version {
+            states.push(format!("may be obsolete by {}", upstream_version));
+        }
+        if states.is_empty() {
+            states.push("ok".to_string());
+        }
+        println!(
+            "{}.{}: {}",
+            check.entry.source,
+            check.entry.key,
+            states.join(", ")
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! To load the config file of the tool
+//!
+//! The project config `.dependencies-patch.toml` in the cargo project is merged with the user
+//! config `~/.config/dependencies-patch/config.toml`, where the project config wins:
+//!
+//! ```toml
+//! # The host alias used for the git repo in the format of `owner/repo`
+//! git-host = "corp"
+//! # The owner used for the git repo given only by its name
+//! fork-owner = "my-team"
+//! # What to do if the package has been patched: `error`, `skip` or `replace`
+//! on-conflict = "replace"
+//! # Where to write the patches: `manifest` or `cargo-config`
+//! patch-location = "cargo-config"
+//!
+//! [hosts]
+//! corp = "https://git.corp.example/{owner}/{repo}.git"
+//!
+//! # The named groups of the patch specs, toggled by `profile enable` and `profile disable`
+//! [profiles.local-dev]
+//! patches = ["log=../log", "serde=gh:serde-rs/serde#branch=fix"]
+//! ```
+
+use std::{collections::BTreeMap, fs, path::PathBuf, sync::OnceLock};
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::manifest::PatchLocation;
+
+/// The name of the project config file
+const PROJECT_CONFIG: &str = ".dependencies-patch.toml";
+
+/// The host aliases which are always available, unless they are overridden by the config
+const BUILTIN_HOSTS: [(&str, &str); 2] = [
+    // Add the extra '/' before the repo name to avoid the error
+    // `points to the same source, but patches must point to different sources`
+    ("gh", "https://github.com/{owner}//{repo}.git"),
+    ("gl", "https://gitlab.com/{owner}/{repo}.git"),
+];
+
+/// What to do if the package has been patched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ConflictPolicy {
+    /// Report an error and keep the existing patch
+    #[default]
+    Error,
+    /// Keep the existing patch with a warning
+    Skip,
+    /// Replace the existing patch with the new one
+    Replace,
+}
+
+/// A named group of patches, which is enabled or disabled as a unit
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Profile {
+    /// The patch specs, such as `log=../log`
+    pub patches: Vec<String>,
+}
+
+/// The config of the tool, where every field is optional
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Config {
+    /// The host alias used for the git repo in the format of `owner/repo`, which is `gh` defaultly
+    pub git_host: Option<String>,
+    /// The owner used for the git repo given only by its name
+    pub fork_owner: Option<String>,
+    /// What to do if the package has been patched
+    pub on_conflict: Option<ConflictPolicy>,
+    /// Where to write the patches
+    pub patch_location: Option<PatchLocation>,
+    /// The host aliases, which map to the url templates with `{owner}` and `{repo}`
+    #[serde(default)]
+    pub hosts: BTreeMap<String, String>,
+    /// The patch profiles by their names
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    /// Merge another config into this one, where the other config wins
+    pub fn merge(&mut self, other: Config) {
+        if other.git_host.is_some() {
+            self.git_host = other.git_host;
+        }
+        if other.fork_owner.is_some() {
+            self.fork_owner = other.fork_owner;
+        }
+        if other.on_conflict.is_some() {
+            self.on_conflict = other.on_conflict;
+        }
+        if other.patch_location.is_some() {
+            self.patch_location = other.patch_location;
+        }
+        self.hosts.extend(other.hosts);
+        self.profiles.extend(other.profiles);
+    }
+
+    /// What to do if the package has been patched
+    pub fn on_conflict(&self) -> ConflictPolicy {
+        self.on_conflict.unwrap_or_default()
+    }
+
+    /// Where to write the patches
+    pub fn patch_location(&self) -> PatchLocation {
+        self.patch_location.unwrap_or_default()
+    }
+
+    /// Get the url template of the host alias
+    pub fn host(&self, alias: &str) -> Option<&str> {
+        self.hosts
+            .get(alias)
+            .map(|template| template.as_str())
+            .or(BUILTIN_HOSTS
+                .iter()
+                .find(|(name, _)| *name == alias)
+                .map(|(_, template)| *template))
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// Get the path of the user config, which follows `$XDG_CONFIG_HOME` if it is set
+fn user_config_path() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(config_home) => PathBuf::from(config_home),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("dependencies-patch").join("config.toml"))
+}
+
+/// Read a config file, where a missing file is an empty config
+fn read_config(path: &PathBuf) -> Result<Config, String> {
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    let config: Config = toml::from_str(&content)
+        .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))?;
+    for (alias, template) in &config.hosts {
+        if !template.contains("{repo}") {
+            return Err(format!(
+                "The template of host {} in {} must contain `{{repo}}`",
+                alias,
+                path.display()
+            ));
+        }
+    }
+    debug_log!("Loaded the config {}", path.display());
+    Ok(config)
+}
+
+/// Load the user config and the project config, where the project config wins
+pub(crate) fn load_config(cargo_path: &String) -> Result<Config, String> {
+    let mut config = match user_config_path() {
+        Some(path) => read_config(&path)?,
+        None => Config::default(),
+    };
+    config.merge(read_config(&project_config_path(cargo_path))?);
+    Ok(config)
+}
+
+/// Get the path of the project config in the cargo project
+pub(crate) fn project_config_path(cargo_path: &String) -> PathBuf {
+    PathBuf::from(cargo_path).join(PROJECT_CONFIG)
+}
+
+/// Set the config used by the whole run
+pub(crate) fn init_config(config: Config) {
+    CONFIG.set(config).unwrap();
+}
+
+/// Get the config of the run
+pub(crate) fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+/// Expand the git repo into a git url
+///
+/// # Arguments
+///
+/// - `repo`: One of the following formats:
+///    - A full git url, which is used directly
+///    - `alias:owner/repo` or `alias:repo`, which uses the url template of the host alias
+///    - `owner/repo` or `repo`, which uses the default git host
+///
+///   The owner can be omitted if the `fork-owner` is configured.
+///
+/// # Return
+///
+/// - Ok(url): The git url
+/// - Err(mes): The error message
+pub(crate) fn expand_git_repo(repo: &str) -> Result<String, String> {
+    if repo.contains("://") {
+        return Ok(repo.to_string());
+    }
+    let config = config();
+    let (alias, name) = match repo.split_once(':') {
+        Some((alias, name)) => (alias, name),
+        None => (config.git_host.as_deref().unwrap_or("gh"), repo),
+    };
+    let template = config
+        .host(alias)
+        .ok_or(format!("The host alias {} is not configured!", alias))?;
+    let (owner, name) = match name.split_once('/') {
+        Some((owner, name)) => (owner, name),
+        None => (
+            config.fork_owner.as_deref().ok_or(format!(
+                "{} is not a valid git repo name, and no fork owner is configured!",
+                repo
+            ))?,
+            name,
+        ),
+    };
+    if owner.is_empty() || name.is_empty() || name.contains('/') {
+        return Err(format!("{} is not a valid git repo name!", repo));
+    }
+    Ok(template.replace("{owner}", owner).replace("{repo}", name))
+}
@@ -0,0 +1,146 @@
+//! To run a cargo command with ephemeral patches
+//!
+//! The patches are resolved as `add` does, but passed to cargo by `--config` arguments
+//! such as `--config 'patch.crates-io.log.path="../log"'` rather than written into any file.
+//! The Cargo.lock generated or updated for the patches is restored after the command, even if
+//! the command fails or Ctrl-C is pressed.
+
+use std::{fs, path::Path};
+
+use toml::Table;
+use toml_edit::Key;
+
+use crate::{
+    arg_parse::ExecArgs,
+    cargo_command::{defer_interrupts, is_interrupted, run_user_command},
+    logger::has_errors,
+    manifest::capture_patches,
+    patch::apply_patch,
+    spec::{parse_spec, read_batch},
+};
+
+/// Translate the patch tables into the `--config` arguments of cargo, one for each field
+fn config_args(patches: &Table) -> Vec<String> {
+    let mut args = Vec::new();
+    let sources = patches.get("patch").and_then(|patch| patch.as_table());
+    for (source, packages) in sources.into_iter().flatten() {
+        let Some(packages) = packages.as_table() else {
+            continue;
+        };
+        for (key, table) in packages {
+            let Some(table) = table.as_table() else {
+                continue;
+            };
+            for (field, value) in table {
+                args.push("--config".to_string());
+                args.push(format!(
+                    "patch.{}.{}.{}={}",
+                    Key::new(source).display_repr(),
+                    Key::new(key).display_repr(),
+                    Key::new(field).display_repr(),
+                    value
+                ));
+            }
+        }
+    }
+    args
+}
+
+/// Resolve the patches and run the cargo command with them
+///
+/// The command runs in the cargo project, so the relative paths of the patches are kept.
+pub(crate) fn exec(cargo_path: &String, args: ExecArgs) {
+    let mut specs = Vec::new();
+    for spec in &args.specs {
+        match parse_spec(spec) {
+            Ok(spec) => specs.push(spec),
+            Err(err) => {
+                error_log!(code = "invalid-spec", "{}", err);
+                return;
+            }
+        }
+    }
+    if let Some(batch) = &args.batch {
+        match read_batch(batch) {
+            Ok(batch) => specs.extend(batch),
+            Err(mes) => {
+                error_log!(code = "invalid-spec", "{}", mes);
+                return;
+            }
+        }
+    }
+    // Resolving the patches may generate the Cargo.lock, so it is snapshotted before that
+    let cargo_lock_path = Path::new(cargo_path).join("Cargo.lock");
+    let cargo_lock = fs::read(&cargo_lock_path).ok();
+    // Keep running on Ctrl-C until the Cargo.lock is restored
+    defer_interrupts();
+    let patches = capture_patches(|| {
+        for spec in specs {
+            apply_patch(cargo_path, spec, false);
+        }
+    });
+    let result = match has_errors() || is_interrupted() {
+        true => Ok(()),
+        false => run_user_command(cargo_path, &args.command, &config_args(&patches)),
+    };
+    // The Cargo.lock which was absent is removed
+    let restored = match &cargo_lock {
+        Some(cargo_lock) => fs::write(&cargo_lock_path, cargo_lock),
+        None => fs::remove_file(&cargo_lock_path).or(Ok(())),
+    };
+    if let Err(err) = restored {
+        error_log!(
+            code = "lockfile-failed",
+            "Failed to restore {}: {}",
+            cargo_lock_path.display(),
+            err
+        );
+    }
+    if let Err(mes) = result {
+        error_log!(code = "exec-failed", "{}", mes);
+    }
+    if is_interrupted() {
+        error_log!(code = "interrupted", "The command is interrupted");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_args_of_the_patch_sources() {
+        let patches = toml::from_str::<Table>(
+            r#"
+[patch.crates-io]
+log = { path = "../log" }
+log2 = { path = "../log2", package = "log" }
+serde = { git = "https://github.com/serde-rs/serde.git", branch = "fix" }
+
+[patch."https://github.com/rust-lang/cargo"]
+cargo-util = { path = "../cargo-util" }
+"#,
+        )
+        .unwrap();
+        let args = config_args(&patches);
+        let configs = args
+            .chunks(2)
+            .map(|pair| {
+                assert_eq!(pair[0], "--config");
+                pair[1].as_str()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            configs,
+            [
+                r#"patch.crates-io.log.path="../log""#,
+                r#"patch.crates-io.log2.package="log""#,
+                r#"patch.crates-io.log2.path="../log2""#,
+                r#"patch.crates-io.serde.branch="fix""#,
+                r#"patch.crates-io.serde.git="https://github.com/serde-rs/serde.git""#,
+                r#"patch."https://github.com/rust-lang/cargo".cargo-util.path="../cargo-util""#,
+            ]
+        );
+        assert!(config_args(&Table::new()).is_empty());
+    }
+}
@@ -1,10 +1,14 @@
-use std::fs::OpenOptions;
-use std::io::Write;
 use toml::Table;
 
-use crate::patch::gen_patch_table;
+use crate::{
+    config::{config, expand_git_repo},
+    manifest::append_patch,
+    output::record_patch,
+    patch::{gen_patch_table, package_alias},
+};
 
 /// The patch git-target information
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum GitInfo {
     /// No specific information
     None,
@@ -17,8 +21,9 @@ pub(crate) enum GitInfo {
 }
 
 /// The patch information
+#[derive(Debug, Clone, PartialEq)]
 pub struct GitPatch {
-    /// The git repository name
+    /// The git repository, such as `owner/repo`, `alias:owner/repo` or a full git url
     git: String,
     /// The real name of the package which may be renamed in the Cargo.toml
     package: Option<String>,
@@ -43,22 +48,22 @@ impl GitPatch {
             info,
         }
     }
-}
 
-/// To check if the git patch format is correct
-///
-/// - The git repo name should be in the format of `owner/repo`
-///
-/// # Return
-///
-/// - Ok((owner, repo)): The owner and repo name of the given patch
-/// - Err(mes): The error message
-fn check_git_patch_format(patch: &GitPatch) -> Result<(&str, &str), String> {
-    let names = patch.git.split('/').collect::<Vec<&str>>();
-    if names.len() != 2 {
-        return Err(format!("{} is not a valid git repo name!", patch.git));
+    /// The real name of the package, if it is renamed
+    pub(crate) fn package(&self) -> Option<&String> {
+        self.package.as_ref()
+    }
+
+    /// Set the real name of the package, if it is renamed
+    pub(crate) fn set_package(&mut self, package: Option<String>) {
+        self.package = package;
+    }
+
+    /// The same patch target for another package of the same family,
+    /// which has no real package name nor version requirement
+    pub(crate) fn for_family_member(&self) -> Self {
+        Self::new(self.git.clone(), None, None, self.info.clone())
     }
-    Ok((names[0], names[1]))
 }
 
 /// Patch the specific package to the git repository
@@ -77,24 +82,25 @@ pub(crate) fn do_git_patch(cargo_path: &String, package_name: &String, patch: Gi
         None => package_name,
     };
 
-    let names = match check_git_patch_format(&patch) {
-        Ok(names) => names,
+    let patch_git = match expand_git_repo(&patch.git) {
+        Ok(url) => url,
         Err(mes) => {
-            error_log!("{}", mes);
+            error_log!(code = "invalid-git-repo", "{}", mes);
             return;
         }
     };
 
     // The table which contains the patch information, will be written to the Cargo.toml
-    let (mut toml_table, package_index) =
-        if let Some(res) = gen_patch_table(cargo_path, package_name, real_package_name) {
-            res
-        } else {
-            return;
-        };
-    // Add the extra '/' before the repo name to avoid the error
-    // `points to the same source, but patches must point to different sources`
-    let patch_git = format!("https://github.com/{}//{}.git", names.0, names.1);
+    let (mut toml_table, package_index, key) = if let Some(res) = gen_patch_table(
+        cargo_path,
+        package_name,
+        real_package_name,
+        patch.version.as_deref(),
+    ) {
+        res
+    } else {
+        return;
+    };
     let patch_toml_table = toml_table.get_mut("patch").unwrap().as_table_mut().unwrap();
     let git_table = patch_toml_table
         .get_mut(&package_index)
@@ -105,7 +111,12 @@ pub(crate) fn do_git_patch(cargo_path: &String, package_name: &String, patch: Gi
     // The table which contains the patch information
     let mut patch_table = Table::new();
     patch_table.insert("git".to_string(), toml::Value::String(patch_git.clone()));
-    if let Some(target_package) = patch.package {
+    // The key differs from the real package name for a renamed package or another version
+    if let Some(target_package) = patch
+        .package
+        .clone()
+        .or(package_alias(&key, real_package_name))
+    {
         patch_table.insert("package".to_string(), toml::Value::String(target_package));
     }
     if let Some(version) = patch.version {
@@ -124,14 +135,11 @@ pub(crate) fn do_git_patch(cargo_path: &String, package_name: &String, patch: Gi
         GitInfo::None => {}
     }
 
-    git_table.insert(package_name.clone(), toml::Value::Table(patch_table));
-    // Write the patch table to the Cargo.toml in appending mode
-    let mut file = OpenOptions::new()
-        .append(true)
-        .open(format!("{}/Cargo.toml", cargo_path))
-        .unwrap();
-    file.write_all("\n".as_bytes()).unwrap();
-    if let Err(mes) = file.write_all(toml::to_string(&toml_table).unwrap().as_bytes()) {
-        error_log!("{}", mes);
+    git_table.insert(key.clone(), toml::Value::Table(patch_table.clone()));
+    // Write the patch table in appending mode
+    if let Err(mes) = append_patch(cargo_path, config().patch_location(), &toml_table) {
+        error_log!(code = "write-failed", "{}", mes);
+        return;
     };
+    record_patch(&package_index, &key, &patch_table);
 }
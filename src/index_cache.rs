@@ -0,0 +1,346 @@
+//! To read the versions of a package from the registry index cached by cargo
+//!
+//! Cargo caches the index entries of every package it has resolved in
+//! `$CARGO_HOME/registry/index/<host>-<hash>/.cache`, so the versions can be looked up offline.
+//! The hash is computed from the index url as cargo does, so the registries on the same host are
+//! told apart.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+
+use crate::cargo_parse::cargo_home;
+
+/// A version of the package in the index
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct IndexVersion {
+    pub version: Version,
+    pub yanked: bool,
+}
+
+/// The fields used of an index entry
+#[derive(Deserialize)]
+struct IndexEntry {
+    vers: String,
+    #[serde(default)]
+    yanked: bool,
+}
+
+/// Get the host of the index url, which is the prefix of the cache directory
+///
+/// e.g. `sparse+https://index.crates.io/` is cached in `index.crates.io-1949cf8c6b5b557f`.
+fn index_host(index: &str) -> &str {
+    let index = index
+        .strip_prefix("sparse+")
+        .or(index.strip_prefix("registry+"))
+        .unwrap_or(index);
+    let index = index.split_once("://").map_or(index, |(_, rest)| rest);
+    index.split(['/', ':']).next().unwrap_or(index)
+}
+
+/// One round of SipHash
+fn sip_round(v: &mut [u64; 4]) {
+    v[0] = v[0].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(13) ^ v[0];
+    v[0] = v[0].rotate_left(32);
+    v[2] = v[2].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(16) ^ v[2];
+    v[0] = v[0].wrapping_add(v[3]);
+    v[3] = v[3].rotate_left(21) ^ v[0];
+    v[2] = v[2].wrapping_add(v[1]);
+    v[1] = v[1].rotate_left(17) ^ v[2];
+    v[2] = v[2].rotate_left(32);
+}
+
+/// Hash the data by SipHash with the zero keys
+///
+/// # Arguments
+///
+/// - `c_rounds`, `d_rounds`: The rounds per message block and in the finalization
+///
+/// - `wide`: Whether to produce the 128-bit hash, whose halves are returned, otherwise the
+///   64-bit hash is returned as the first one
+fn sip_hash(data: &[u8], c_rounds: usize, d_rounds: usize, wide: bool) -> (u64, u64) {
+    let mut v = [
+        0x736f6d6570736575,
+        0x646f72616e646f6d ^ if wide { 0xee } else { 0 },
+        0x6c7967656e657261,
+        0x7465646279746573,
+    ];
+    let compress = |v: &mut [u64; 4], message: u64| {
+        v[3] ^= message;
+        (0..c_rounds).for_each(|_| sip_round(v));
+        v[0] ^= message;
+    };
+    let mut blocks = data.chunks_exact(8);
+    for block in &mut blocks {
+        compress(&mut v, u64::from_le_bytes(block.try_into().unwrap()));
+    }
+    let mut last = [0; 8];
+    last[..blocks.remainder().len()].copy_from_slice(blocks.remainder());
+    compress(
+        &mut v,
+        u64::from_le_bytes(last) | ((data.len() as u64 & 0xff) << 56),
+    );
+    v[2] ^= if wide { 0xee } else { 0xff };
+    (0..d_rounds).for_each(|_| sip_round(&mut v));
+    let first = v[0] ^ v[1] ^ v[2] ^ v[3];
+    if !wide {
+        return (first, 0);
+    }
+    v[1] ^= 0xdd;
+    (0..d_rounds).for_each(|_| sip_round(&mut v));
+    (first, v[0] ^ v[1] ^ v[2] ^ v[3])
+}
+
+/// Get the names of the cache directories of the registry, such as `index.crates.io-1949cf8c6b5b557f`
+///
+/// Cargo hashes the kind of the source and its url. Since cargo 1.85 the kind is hashed as a byte
+/// by the 128-bit SipHash-1-3, and before that as an 8-byte integer by SipHash-2-4, so the names
+/// of both are returned.
+fn cache_dir_names(index: &str) -> Vec<String> {
+    // The kind of the source is 2 for a git registry and 3 for a sparse registry
+    let (kind, url) = match index.strip_prefix("sparse+") {
+        Some(_) => (3u8, index),
+        None => (2, index.strip_prefix("registry+").unwrap_or(index)),
+    };
+    // The url is normalized with the path `/` for a bare host
+    let mut url = url.to_string();
+    if url
+        .split_once("://")
+        .is_some_and(|(_, rest)| !rest.contains('/'))
+    {
+        url.push('/');
+    }
+    let hashed = |kind: &[u8]| [kind, url.as_bytes(), &[0xff]].concat();
+    let (first, second) = sip_hash(&hashed(&[kind]), 1, 3, true);
+    let current = first.wrapping_mul(3).wrapping_add(second);
+    let (legacy, _) = sip_hash(&hashed(&(kind as u64).to_le_bytes()), 2, 4, false);
+    [current, legacy]
+        .iter()
+        .map(|hash| format!("{}-{}", index_host(index), hex(&hash.to_le_bytes())))
+        .collect()
+}
+
+/// Encode the bytes in lowercase hex
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Get the relative path of the package in the index, such as `st/rs/strsim`
+fn package_path(name: &str) -> PathBuf {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => Path::new("1").join(&name),
+        2 => Path::new("2").join(&name),
+        3 => Path::new("3").join(&name[..1]).join(&name),
+        _ => Path::new(&name[..2]).join(&name[2..4]).join(&name),
+    }
+}
+
+/// Parse a cache file
+///
+/// The file starts with the cache version byte, the index version as a little-endian u32 and
+/// the revision ending with `\0`, followed by the pairs of the version and the JSON entry,
+/// each ending with `\0`.
+fn parse_cache(content: &[u8]) -> Result<Vec<IndexVersion>, String> {
+    if content.len() < 5 {
+        return Err("The cache file is truncated".to_string());
+    }
+    let mut fields = content[5..].split(|byte| *byte == 0);
+    // Skip the revision
+    fields.next();
+    let mut versions = Vec::new();
+    while let (Some(_), Some(entry)) = (fields.next(), fields.next()) {
+        if entry.is_empty() {
+            continue;
+        }
+        let entry: IndexEntry = serde_json::from_slice(entry)
+            .map_err(|err| format!("Failed to parse the index entry: {}", err))?;
+        if let Ok(version) = Version::parse(&entry.vers) {
+            versions.push(IndexVersion {
+                version,
+                yanked: entry.yanked,
+            });
+        }
+    }
+    versions.sort_by(|a, b| a.version.cmp(&b.version));
+    Ok(versions)
+}
+
+/// Get all the cached versions of the package in the registry, from the oldest
+///
+/// # Arguments
+///
+/// - `index`: The index url of the registry, such as `sparse+https://index.crates.io/`
+///
+/// - `name`: The name of the package
+///
+/// # Return
+///
+/// - Ok(versions): The versions found in the cache
+/// - Err(mes): The package is not cached for the registry
+pub(crate) fn cached_versions(index: &str, name: &str) -> Result<Vec<IndexVersion>, String> {
+    let index_dir = cargo_home()
+        .ok_or("The cargo home is unknown".to_string())?
+        .join("registry")
+        .join("index");
+    let mut versions = Vec::new();
+    let mut found = false;
+    for dir_name in cache_dir_names(index) {
+        let cache_file = index_dir
+            .join(dir_name)
+            .join(".cache")
+            .join(package_path(name));
+        let Ok(content) = fs::read(&cache_file) else {
+            continue;
+        };
+        trace_log!("Read the index cache {}", cache_file.display());
+        found = true;
+        for version in
+            parse_cache(&content).map_err(|mes| format!("{}: {}", cache_file.display(), mes))?
+        {
+            if !versions.contains(&version) {
+                versions.push(version);
+            }
+        }
+    }
+    if !found {
+        return Err(format!(
+            "The package {} is not in the cached index of {}, run `cargo update` online to cache it",
+            name, index
+        ));
+    }
+    versions.sort_by(|a, b| a.version.cmp(&b.version));
+    Ok(versions)
+}
+
+/// Get the cached versions matching the requirement, from the oldest
+pub(crate) fn matching_versions(versions: &[IndexVersion], req: &VersionReq) -> Vec<IndexVersion> {
+    versions
+        .iter()
+        .filter(|version| req.matches(&version.version))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a cache file with the header and the pairs of the version and the entry
+    fn cache_file(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut content = vec![3];
+        content.extend(2u32.to_le_bytes());
+        content.extend(b"etag: \"abc\"\0");
+        for (version, entry) in entries {
+            content.extend(version.as_bytes());
+            content.push(0);
+            content.extend(entry.as_bytes());
+            content.push(0);
+        }
+        content
+    }
+
+    fn version(version: &str, yanked: bool) -> IndexVersion {
+        IndexVersion {
+            version: Version::parse(version).unwrap(),
+            yanked,
+        }
+    }
+
+    #[test]
+    fn parse_cache_sorts_versions() {
+        let content = cache_file(&[
+            ("0.4.22", r#"{"name":"log","vers":"0.4.22","deps":[]}"#),
+            ("0.4.3", r#"{"name":"log","vers":"0.4.3","yanked":true}"#),
+            ("0.3.9", r#"{"name":"log","vers":"0.3.9","yanked":false}"#),
+        ]);
+        assert_eq!(
+            parse_cache(&content).unwrap(),
+            vec![
+                version("0.3.9", false),
+                version("0.4.3", true),
+                version("0.4.22", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_cache_skips_empty_and_invalid_versions() {
+        let content = cache_file(&[
+            ("1.0.0", ""),
+            ("bad", r#"{"vers":"not-a-version"}"#),
+            ("1.0.1", r#"{"vers":"1.0.1"}"#),
+        ]);
+        assert_eq!(
+            parse_cache(&content).unwrap(),
+            vec![version("1.0.1", false)]
+        );
+        assert_eq!(parse_cache(&cache_file(&[])).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn parse_cache_rejects_broken_files() {
+        assert!(parse_cache(&[3, 2, 0]).is_err());
+        let content = cache_file(&[("1.0.0", "{not json")]);
+        assert!(parse_cache(&content)
+            .unwrap_err()
+            .starts_with("Failed to parse the index entry"));
+    }
+
+    #[test]
+    fn cache_dir_names_of_crates_io() {
+        assert_eq!(
+            cache_dir_names("sparse+https://index.crates.io/"),
+            [
+                "index.crates.io-1949cf8c6b5b557f",
+                "index.crates.io-6f17d22bba15001f"
+            ]
+        );
+        assert_eq!(
+            cache_dir_names("registry+https://github.com/rust-lang/crates.io-index"),
+            ["github.com-25cdd57fae9f0462", "github.com-1ecc6299db9ec823"]
+        );
+        assert_eq!(
+            cache_dir_names("https://github.com/rust-lang/crates.io-index"),
+            cache_dir_names("registry+https://github.com/rust-lang/crates.io-index")
+        );
+    }
+
+    #[test]
+    fn cache_dir_names_tell_registries_on_the_same_host_apart() {
+        let crates_io = cache_dir_names("https://github.com/rust-lang/crates.io-index");
+        let other = cache_dir_names("https://github.com/my-org/index");
+        assert!(other.iter().all(|name| name.starts_with("github.com-")));
+        assert!(other.iter().all(|name| !crates_io.contains(name)));
+        assert_eq!(
+            cache_dir_names("sparse+https://mirror.example"),
+            cache_dir_names("sparse+https://mirror.example/")
+        );
+    }
+
+    #[test]
+    fn index_host_and_package_path() {
+        assert_eq!(
+            index_host("sparse+https://index.crates.io/"),
+            "index.crates.io"
+        );
+        assert_eq!(
+            index_host("registry+https://github.com/rust-lang/crates.io-index"),
+            "github.com"
+        );
+        assert_eq!(
+            index_host("https://mirror.example:8080/index"),
+            "mirror.example"
+        );
+        assert_eq!(package_path("a"), Path::new("1/a"));
+        assert_eq!(package_path("cc"), Path::new("2/cc"));
+        assert_eq!(package_path("Log"), Path::new("3/l/log"));
+        assert_eq!(package_path("strsim"), Path::new("st/rs/strsim"));
+    }
+}
@@ -1,29 +1,179 @@
 //! Do patch for registry
 //!
-//! Now it only supports crates-io
-
-use std::{fs::OpenOptions, io::Write};
+//! The package is patched to a version of another registry configured in `[registries]`
+//! of the cargo config, because cargo rejects the patch pointing to the same source.
 
+use semver::VersionReq;
 use toml::Table;
 
-use crate::patch::gen_patch_table;
+use crate::{
+    cargo_parse::{pick_package, CargoPackage, Dependency},
+    config::config,
+    index_cache::{cached_versions, matching_versions},
+    manifest::append_patch,
+    output::record_patch,
+    patch::{gen_patch_table, package_alias},
+    registry::{registry_index, registry_source_key, CRATES_IO},
+};
 
 /// The information for index patch
+#[derive(Debug, Clone, PartialEq)]
 pub struct IndexPatch {
     /// The real name of the package which may be renamed in the Cargo.toml
     package: Option<String>,
     /// The version of the patch
     version: String,
+    /// The name of the registry to be patched to
+    registry: Option<String>,
+    /// Whether to pick the newest cached version matching the version requirement
+    latest_matching: bool,
 }
 
 impl IndexPatch {
     /// Create a new index patch
-    pub fn new(package: Option<String>, version: String) -> Self {
-        Self { package, version }
+    pub fn new(package: Option<String>, version: String, registry: Option<String>) -> Self {
+        Self {
+            package,
+            version,
+            registry,
+            latest_matching: false,
+        }
+    }
+
+    /// Pick the newest cached version matching the version requirement
+    pub(crate) fn set_latest_matching(&mut self, latest_matching: bool) {
+        self.latest_matching = latest_matching;
+    }
+
+    /// The real name of the package, if it is renamed
+    pub(crate) fn package(&self) -> Option<&String> {
+        self.package.as_ref()
+    }
+
+    /// Set the real name of the package, if it is renamed
+    pub(crate) fn set_package(&mut self, package: Option<String>) {
+        self.package = package;
     }
 }
 
-/// Patch the specific package to the git repository
+/// Check that the target registry differs from the source of the package
+///
+/// # Return
+///
+/// - Ok(()): The registry is configured and differs from the source of the package
+/// - Err(mes): The error message
+fn check_registry(
+    cargo_path: &String,
+    package: &CargoPackage,
+    registry: &str,
+) -> Result<(), String> {
+    let index = registry_index(cargo_path, registry)?;
+    if let Dependency::Registry(source) = package.parse_dependency() {
+        if source == registry || source == registry_source_key(&index) {
+            return Err(format!(
+                "The package {} already comes from the registry {}, patches must point to a different registry!",
+                package.name, registry
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check the version requirement against the cached index of the target registry
+///
+/// The matching versions are listed, and the missing or yanked versions are warned.
+/// If the index isn't cached, the version is not checked unless `latest_matching` is set.
+///
+/// # Return
+///
+/// - Ok(version): The version to be written, which is the newest matching version if `latest_matching` is set
+/// - Err(mes): The error message
+fn check_version(
+    cargo_path: &String,
+    real_package_name: &String,
+    patch: &IndexPatch,
+) -> Result<String, String> {
+    let req = VersionReq::parse(&patch.version).map_err(|err| {
+        format!(
+            "{} is not a valid version requirement: {}",
+            patch.version, err
+        )
+    })?;
+    let registry = patch.registry.as_deref().unwrap_or(CRATES_IO);
+    let index = registry_index(cargo_path, registry)?;
+    let versions = match cached_versions(&index, real_package_name) {
+        Ok(versions) => versions,
+        Err(mes) if !patch.latest_matching => {
+            warn_log!("{}, the version is not checked", mes);
+            return Ok(patch.version.clone());
+        }
+        Err(mes) => return Err(mes),
+    };
+    let matching = matching_versions(&versions, &req);
+    let available = matching
+        .iter()
+        .filter(|version| !version.yanked)
+        .collect::<Vec<_>>();
+    if matching.is_empty() {
+        let latest = versions
+            .iter()
+            .rev()
+            .take(5)
+            .map(|version| version.version.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mes = format!(
+            "No version of {} matches {} in the cached index of {}, the latest are: {}",
+            real_package_name, patch.version, registry, latest
+        );
+        if patch.latest_matching {
+            return Err(mes);
+        }
+        warn_log!("{}", mes);
+        return Ok(patch.version.clone());
+    }
+    // Only the newest ones are listed if there are too many
+    let mut listed = matching
+        .iter()
+        .rev()
+        .take(10)
+        .map(|version| match version.yanked {
+            true => format!("{} (yanked)", version.version),
+            false => version.version.to_string(),
+        })
+        .collect::<Vec<_>>();
+    if matching.len() > listed.len() {
+        listed.push(format!("and {} older", matching.len() - listed.len()));
+    }
+    info_log!(
+        "The versions of {} matching {}: {}",
+        real_package_name,
+        patch.version,
+        listed.join(", ")
+    );
+    let Some(latest) = available.last() else {
+        let mes = format!(
+            "All the versions of {} matching {} are yanked",
+            real_package_name, patch.version
+        );
+        if patch.latest_matching {
+            return Err(mes);
+        }
+        warn_log!("{}", mes);
+        return Ok(patch.version.clone());
+    };
+    if !patch.latest_matching {
+        return Ok(patch.version.clone());
+    }
+    info_log!(
+        "Pick the newest version {} of {}",
+        latest.version,
+        real_package_name
+    );
+    Ok(latest.version.to_string())
+}
+
+/// Patch the specific package to a version of the registry
 ///
 /// # Arguments
 ///
@@ -31,7 +181,7 @@ impl IndexPatch {
 ///
 /// - `package_name`: The name of the package to be patched
 ///
-/// - `version`: The version of the patch
+/// - `patch`: The version and the registry of the patch
 pub(crate) fn do_index_patch(cargo_path: &String, package_name: &String, patch: &IndexPatch) {
     // If the package has been renamed, the `package` field in the patch should be used because it is the real package name.
     let real_package_name = match &patch.package {
@@ -39,13 +189,45 @@ pub(crate) fn do_index_patch(cargo_path: &String, package_name: &String, patch:
         None => package_name,
     };
 
-    // The table which contains the patch information, will be written to the Cargo.toml
-    let (mut toml_table, package_index) =
-        if let Some(res) = gen_patch_table(cargo_path, package_name, real_package_name) {
-            res
-        } else {
+    let package = match pick_package(cargo_path, real_package_name, Some(&patch.version)) {
+        Ok(package) => package,
+        Err(mes) => {
+            error_log!(code = "package-not-found", "{}", mes);
+            return;
+        }
+    };
+    match &patch.registry {
+        Some(registry) => {
+            if let Err(mes) = check_registry(cargo_path, &package, registry) {
+                error_log!(code = "invalid-registry", "{}", mes);
+                return;
+            }
+        }
+        None => {
+            error_log!(
+                code = "invalid-registry",
+                "No registry is given for {}, cargo rejects the patch pointing to the same registry, use `--registry` to patch to another one",
+                package_name
+            );
+            return;
+        }
+    }
+    let version = match check_version(cargo_path, real_package_name, patch) {
+        Ok(version) => version,
+        Err(mes) => {
+            error_log!(code = "invalid-version", "{}", mes);
             return;
-        };
+        }
+    };
+
+    // The table which contains the patch information, will be written to the Cargo.toml
+    let (mut toml_table, package_index, key) = if let Some(res) =
+        gen_patch_table(cargo_path, package_name, real_package_name, Some(&version))
+    {
+        res
+    } else {
+        return;
+    };
 
     let patch_toml_table = toml_table.get_mut("patch").unwrap().as_table_mut().unwrap();
     let index_table = patch_toml_table
@@ -57,19 +239,27 @@ pub(crate) fn do_index_patch(cargo_path: &String, package_name: &String, patch:
     // The table which contains the patch information
     let mut patch_table = Table::new();
 
-    patch_table.insert(
-        "version".to_string(),
-        toml::Value::String(patch.version.clone()),
-    );
+    patch_table.insert("version".to_string(), toml::Value::String(version));
+    if let Some(registry) = &patch.registry {
+        patch_table.insert(
+            "registry".to_string(),
+            toml::Value::String(registry.clone()),
+        );
+    }
+    // The key differs from the real package name for a renamed package or another version
+    if let Some(target_package) = patch
+        .package
+        .clone()
+        .or(package_alias(&key, real_package_name))
+    {
+        patch_table.insert("package".to_string(), toml::Value::String(target_package));
+    }
 
-    index_table.insert(package_name.clone(), toml::Value::Table(patch_table));
-    // Write the patch table to the Cargo.toml in appending mode
-    let mut file = OpenOptions::new()
-        .append(true)
-        .open(format!("{}/Cargo.toml", cargo_path))
-        .unwrap();
-    file.write_all("\n".as_bytes()).unwrap();
-    if let Err(mes) = file.write_all(toml::to_string(&toml_table).unwrap().as_bytes()) {
-        error_log!("{}", mes);
+    index_table.insert(key.clone(), toml::Value::Table(patch_table.clone()));
+    // Write the patch table in appending mode
+    if let Err(mes) = append_patch(cargo_path, config().patch_location(), &toml_table) {
+        error_log!(code = "write-failed", "{}", mes);
+        return;
     };
+    record_patch(&package_index, &key, &patch_table);
 }
@@ -0,0 +1,491 @@
+//! To record the patch operations in a journal, so they can be undone
+//!
+//! The journal is stored in `.dependencies-patch/journal.json`. Every operation records the
+//! patch entries it changed with their contents before and after, so an operation can be undone
+//! even after other edits to the manifest, as long as its entries haven't been changed since.
+//! The provenances of the changed entries are recorded too, so they are restored by the undo.
+//! An operation which changes more than the patch entries, such as `migrate`, is recorded as
+//! irreversible, and the older operations can't be undone past it.
+
+use std::{fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use toml::Table;
+
+use crate::{
+    logger::error_count,
+    manifest::{read_patches, remove_patch, set_patch, PatchEntry, PatchLocation},
+    output::{is_json, record_data},
+    provenance::{record_provenance, remove_provenance, Provenance},
+    transaction::{state_dir, utc_now, with_transaction, write_raw_file},
+};
+
+/// A patch entry changed by an operation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct EntryChange {
+    pub location: PatchLocation,
+    /// The key of the source table, such as `crates-io` or a git url
+    pub source: String,
+    /// The key of the package table
+    pub key: String,
+    /// The contents before the operation, where `None` means the entry didn't exist
+    pub before: Option<Table>,
+    /// The contents after the operation, where `None` means the entry is removed
+    pub after: Option<Table>,
+    /// The provenance before the operation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+impl EntryChange {
+    /// Describe the change, such as `added crates-io.log`
+    fn describe(&self) -> String {
+        let kind = match (&self.before, &self.after) {
+            (None, _) => "added",
+            (_, None) => "removed",
+            _ => "updated",
+        };
+        format!("{} {}.{}", kind, self.source, self.key)
+    }
+}
+
+/// An operation in the journal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Operation {
+    /// The increasing id of the operation
+    pub id: u64,
+    /// The UTC time of the operation
+    pub time: String,
+    /// The arguments of the command line
+    pub args: Vec<String>,
+    pub changes: Vec<EntryChange>,
+    /// Why the operation can't be undone
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub irreversible: Option<String>,
+}
+
+/// The path of the journal file
+fn journal_path(cargo_path: &String) -> PathBuf {
+    state_dir(cargo_path).join("journal.json")
+}
+
+/// Read the operations in the journal, from the oldest
+fn read_journal(cargo_path: &String) -> Result<Vec<Operation>, String> {
+    let path = journal_path(cargo_path);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    serde_json::from_str(&content)
+        .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))
+}
+
+/// Write the operations into the journal in the transaction
+fn write_journal(cargo_path: &String, operations: &[Operation]) -> Result<(), String> {
+    let content = serde_json::to_string_pretty(operations).unwrap();
+    write_raw_file(&journal_path(cargo_path), content.as_bytes())
+}
+
+/// Compare the patch entries before and after an operation
+fn diff_entries(before: &[PatchEntry], after: &[PatchEntry]) -> Vec<EntryChange> {
+    let same = |a: &PatchEntry, b: &PatchEntry| {
+        a.location == b.location && a.source == b.source && a.key == b.key
+    };
+    let mut changes = Vec::new();
+    for entry in before {
+        let new = after.iter().find(|new| same(entry, new));
+        if new.is_none_or(|new| new.table != entry.table || new.provenance != entry.provenance) {
+            changes.push(EntryChange {
+                location: entry.location,
+                source: entry.source.clone(),
+                key: entry.key.clone(),
+                before: Some(entry.table.clone()),
+                after: new.map(|new| new.table.clone()),
+                provenance: entry.provenance.clone(),
+            });
+        }
+    }
+    for entry in after {
+        if !before.iter().any(|old| same(entry, old)) {
+            changes.push(EntryChange {
+                location: entry.location,
+                source: entry.source.clone(),
+                key: entry.key.clone(),
+                before: None,
+                after: Some(entry.table.clone()),
+                provenance: None,
+            });
+        }
+    }
+    changes
+}
+
+/// Run the command in a transaction, and record the patch entries it changes in the journal
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project
+///
+/// - `args`: The arguments of the command line, without the binary name
+///
+/// - `command`: The command to run
+pub(crate) fn with_journal(cargo_path: &String, args: Vec<String>, command: impl FnOnce()) {
+    journal_operation(cargo_path, args, None, command);
+}
+
+/// Run the command in a transaction, and record it in the journal as an operation which can't be
+/// undone
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project
+///
+/// - `args`: The arguments of the command line, without the binary name
+///
+/// - `reason`: Why the operation can't be undone
+///
+/// - `command`: The command to run
+pub(crate) fn with_irreversible_journal(
+    cargo_path: &String,
+    args: Vec<String>,
+    reason: &str,
+    command: impl FnOnce(),
+) {
+    journal_operation(cargo_path, args, Some(reason.to_string()), command);
+}
+
+/// Run the command in a transaction, and record the operation if it changes any patch entry
+fn journal_operation(
+    cargo_path: &String,
+    args: Vec<String>,
+    irreversible: Option<String>,
+    command: impl FnOnce(),
+) {
+    with_transaction(cargo_path, || {
+        let errors = error_count();
+        let before = read_patches(cargo_path).unwrap_or_default();
+        command();
+        if error_count() > errors {
+            return;
+        }
+        let changes = diff_entries(&before, &read_patches(cargo_path).unwrap_or_default());
+        if changes.is_empty() {
+            return;
+        }
+        let result = read_journal(cargo_path).and_then(|mut operations| {
+            operations.push(Operation {
+                id: operations.last().map_or(1, |last| last.id + 1),
+                time: utc_now(),
+                args,
+                changes,
+                irreversible,
+            });
+            write_journal(cargo_path, &operations)
+        });
+        if let Err(mes) = result {
+            error_log!(code = "journal-failed", "{}", mes);
+        }
+    });
+}
+
+/// Show the operations in the journal, from the newest
+pub(crate) fn history(cargo_path: &String) {
+    let operations = match read_journal(cargo_path) {
+        Ok(operations) => operations,
+        Err(mes) => {
+            error_log!(code = "journal-failed", "{}", mes);
+            return;
+        }
+    };
+    if is_json() {
+        record_data(&operations.iter().rev().collect::<Vec<_>>());
+        return;
+    }
+    if operations.is_empty() {
+        info_log!("There is no operation in the history");
+        return;
+    }
+    for operation in operations.iter().rev() {
+        println!(
+            "#{} {} {}: {}{}",
+            operation.id,
+            operation.time,
+            operation.args.join(" "),
+            operation
+                .changes
+                .iter()
+                .map(|change| change.describe())
+                .collect::<Vec<_>>()
+                .join(", "),
+            match operation.irreversible {
+                Some(_) => " (can't be undone)",
+                None => "",
+            }
+        );
+    }
+}
+
+/// Revert a change, which fails if the entry has been changed since the operation
+fn revert_change(
+    cargo_path: &String,
+    entries: &[PatchEntry],
+    operation: &Operation,
+    change: &EntryChange,
+) -> Result<(), String> {
+    let current = entries.iter().find(|entry| {
+        entry.location == change.location
+            && entry.source == change.source
+            && entry.key == change.key
+    });
+    if current.map(|entry| &entry.table) != change.after.as_ref() {
+        return Err(format!(
+            "The patch {}.{} in {} has been changed since the operation #{}, it can't be undone",
+            change.source,
+            change.key,
+            change.location.file_name(),
+            operation.id
+        ));
+    }
+    let Some(table) = &change.before else {
+        remove_patch(cargo_path, change.location, &change.source, &change.key)?;
+        return remove_provenance(cargo_path, change.location, &change.source, &change.key);
+    };
+    set_patch(
+        cargo_path,
+        change.location,
+        &change.source,
+        &change.key,
+        table,
+    )?;
+    match &change.provenance {
+        Some(provenance) => {
+            let entry = PatchEntry {
+                source: change.source.clone(),
+                key: change.key.clone(),
+                package: table
+                    .get("package")
+                    .and_then(|package| package.as_str())
+                    .unwrap_or(&change.key)
+                    .to_string(),
+                table: table.clone(),
+                location: change.location,
+                provenance: None,
+            };
+            record_provenance(cargo_path, &entry, provenance, false)
+        }
+        None => remove_provenance(cargo_path, change.location, &change.source, &change.key),
+    }
+}
+
+/// Undo the newest operations in the journal
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project
+///
+/// - `count`: The number of the operations to undo
+pub(crate) fn undo(cargo_path: &String, count: usize) {
+    with_transaction(cargo_path, || {
+        let mut operations = match read_journal(cargo_path) {
+            Ok(operations) => operations,
+            Err(mes) => {
+                error_log!(code = "journal-failed", "{}", mes);
+                return;
+            }
+        };
+        if operations.len() < count {
+            error_log!(
+                code = "undo-failed",
+                "There are only {} operations in the history",
+                operations.len()
+            );
+            return;
+        }
+        let undone = operations.split_off(operations.len() - count);
+        if let Some(operation) = undone
+            .iter()
+            .find(|operation| operation.irreversible.is_some())
+        {
+            error_log!(
+                code = "undo-failed",
+                "The operation #{} `{}` can't be undone because {}, restore the files by git instead",
+                operation.id,
+                operation.args.join(" "),
+                operation.irreversible.as_deref().unwrap_or_default()
+            );
+            return;
+        }
+        for operation in undone.iter().rev() {
+            // Read the entries again since the previous operation may touch them
+            let entries = match read_patches(cargo_path) {
+                Ok(entries) => entries,
+                Err(mes) => {
+                    error_log!(code = "manifest-failed", "{}", mes);
+                    return;
+                }
+            };
+            for change in operation.changes.iter().rev() {
+                if let Err(mes) = revert_change(cargo_path, &entries, operation, change) {
+                    error_log!(code = "undo-failed", "{}", mes);
+                    return;
+                }
+            }
+            info_log!(
+                "Undid the operation #{} `{}`",
+                operation.id,
+                operation.args.join(" ")
+            );
+        }
+        if let Err(mes) = write_journal(cargo_path, &operations) {
+            error_log!(code = "journal-failed", "{}", mes);
+            return;
+        }
+        record_data(&undone);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(key: &str, path: &str, reason: Option<&str>) -> PatchEntry {
+        let mut table = Table::new();
+        table.insert("path".to_string(), path.into());
+        PatchEntry {
+            source: "crates-io".to_string(),
+            key: key.to_string(),
+            package: key.to_string(),
+            table,
+            location: PatchLocation::Manifest,
+            provenance: reason.map(|reason| Provenance {
+                reason: Some(reason.to_string()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn diff_entries_records_the_changes_with_the_provenances() {
+        let before = [
+            entry("log", "../log", Some("fork")),
+            entry("rand", "../rand", None),
+            entry("ring", "../ring", None),
+            entry("serde", "../serde", Some("fix")),
+        ];
+        let after = [
+            entry("log", "../log", Some("fork")),
+            entry("rand", "../rand2", None),
+            entry("ring", "../ring", Some("tls")),
+            entry("time", "../time", Some("new")),
+        ];
+        let changes = diff_entries(&before, &after);
+        let described = changes
+            .iter()
+            .map(|change| change.describe())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            described,
+            [
+                "updated crates-io.rand",
+                "updated crates-io.ring",
+                "removed crates-io.serde",
+                "added crates-io.time",
+            ]
+        );
+        assert_eq!(changes[2].before, Some(before[3].table.clone()));
+        assert_eq!(changes[2].after, None);
+        assert_eq!(changes[2].provenance, before[3].provenance);
+        assert_eq!(changes[3].before, None);
+        assert_eq!(changes[3].provenance, None);
+        assert!(diff_entries(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn revert_changes_restores_the_patches_and_the_provenances() {
+        let dir =
+            std::env::temp_dir().join(format!("dependencies-patch-journal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            concat!(
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n",
+                "[package.metadata.dependencies-patch.patches.crates-io.log]\n",
+                "reason = \"fork\"\n\n",
+                "[patch.crates-io]\nlog = { path = \"../log\" }\n",
+                "rand = { path = \"../rand\" }\n",
+            ),
+        )
+        .unwrap();
+        let cargo_path = dir.to_string_lossy().to_string();
+        let operation = |changes| Operation {
+            id: 1,
+            time: utc_now(),
+            args: Vec::new(),
+            changes,
+            irreversible: None,
+        };
+        let revert = |operation: &Operation| {
+            let entries = read_patches(&cargo_path)?;
+            for change in operation.changes.iter().rev() {
+                revert_change(&cargo_path, &entries, operation, change)?;
+            }
+            Ok::<_, String>(())
+        };
+
+        // Undo the removal of a patch with its provenance
+        let original = read_patches(&cargo_path).unwrap();
+        remove_patch(&cargo_path, PatchLocation::Manifest, "crates-io", "log").unwrap();
+        remove_provenance(&cargo_path, PatchLocation::Manifest, "crates-io", "log").unwrap();
+        let removal = operation(diff_entries(&original, &read_patches(&cargo_path).unwrap()));
+        revert(&removal).unwrap();
+        let restored = read_patches(&cargo_path).unwrap();
+
+        // Undo the addition of a patch with its provenance
+        let mut added = entry("time", "../time", Some("new"));
+        set_patch(
+            &cargo_path,
+            added.location,
+            &added.source,
+            &added.key,
+            &added.table,
+        )
+        .unwrap();
+        let provenance = added.provenance.take().unwrap();
+        record_provenance(&cargo_path, &added, &provenance, false).unwrap();
+        let addition = operation(diff_entries(&restored, &read_patches(&cargo_path).unwrap()));
+        revert(&addition).unwrap();
+        let reverted = read_patches(&cargo_path).unwrap();
+
+        // An entry changed since the operation can't be reverted
+        let mut table = Table::new();
+        table.insert("path".to_string(), "../rand2".into());
+        set_patch(
+            &cargo_path,
+            PatchLocation::Manifest,
+            "crates-io",
+            "rand",
+            &table,
+        )
+        .unwrap();
+        let update = operation(diff_entries(&reverted, &read_patches(&cargo_path).unwrap()));
+        set_patch(
+            &cargo_path,
+            PatchLocation::Manifest,
+            "crates-io",
+            "rand",
+            &original[1].table,
+        )
+        .unwrap();
+        let conflict = revert(&update);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(removal.changes.len(), 1);
+        assert_eq!(restored, original);
+        assert_eq!(addition.changes.len(), 1);
+        assert_eq!(reverted, original);
+        assert!(conflict
+            .unwrap_err()
+            .contains("has been changed since the operation #1"));
+    }
+}
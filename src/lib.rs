@@ -0,0 +1,159 @@
+//! A simple tool to patch cargo dependencies with command line commands.
+//!
+//! # Installation
+//! Simply run
+//! ```sh
+//! $ cargo install dependencies-patch
+//! ```
+//!
+//! # Usage
+//!
+//! The `Cargo.toml` for `example_project` looks like this:
+//!
+//! ```toml
+//! [package]
+//! edition = "2021"
+//! name = "project1"
+//! version = "0.1.0"
+//! [workspace]
+//!
+//! [dependencies]
+//! log = "0.4"
+//! ```
+//!
+//! Then run the following command to patch the `log` dependency to a git repository:
+//!
+//! ```sh
+//! $ dependencies-patch --help
+//!
+//! $ dependencies-patch -c /path/to/example_project add -n log -t git --git-repo rust-lang/log
+//! ```
+//!
+//! After running the command, the `Cargo.toml` will be updated to:
+//!
+//! ```toml
+//! [package]
+//! edition = "2021"
+//! name = "project1"
+//! version = "0.1.0"
+//!
+//! [workspace]
+//!
+//! [dependencies]
+//! log = "0.4"
+//!
+//! [patch.crates-io.log]
+//! git = "https://github.com/rust-lang//log.git"
+//! ```
+//!
+//! The patches can be listed by `list`, checked against the `Cargo.lock` by `status`
+//! and removed by `remove`.
+//!
+//! # Cargo subcommand
+//!
+//! The `cargo-dpatch` binary is also installed, so the tool can run as `cargo dpatch`
+//! with the `--manifest-path` convention of cargo:
+//!
+//! ```sh
+//! $ cargo dpatch --manifest-path /path/to/example_project/Cargo.toml add -n log -t path --patch-path ../log
+//! ```
+use std::process::ExitCode;
+
+#[macro_use]
+mod logger;
+mod arg_parse;
+use arg_parse::{parse_args, Command, ProfileCommand};
+use journal::with_journal;
+use patch::patch;
+mod cargo_command;
+mod cargo_parse;
+mod check;
+mod config;
+mod exec;
+mod git_patch;
+mod index_cache;
+mod index_patch;
+mod journal;
+mod lint;
+mod list;
+mod manifest;
+mod migrate;
+mod monorepo;
+mod output;
+mod patch;
+mod path_patch;
+mod profile;
+mod provenance;
+mod registry;
+mod remove;
+mod report;
+mod spec;
+mod sync;
+mod transaction;
+mod why;
+mod with;
+
+/// Run the tool with the arguments of the command line, including the binary name
+///
+/// # Return
+///
+/// The exit code of the process, which is a failure if any error is logged
+pub fn run(args: Vec<String>) -> ExitCode {
+    // The arguments are recorded in the history of the patch operations
+    let raw_args = args.iter().skip(1).cloned().collect::<Vec<_>>();
+    let args = if let Some(args) = parse_args(args) {
+        args
+    } else {
+        output::finish();
+        return exit_code();
+    };
+
+    let cargo_path = args.cargo_path[0].clone();
+    let multiple = args.cargo_path.len() > 1 || args.recursive.is_some();
+    match args.command {
+        Command::Add(add_args) if multiple => {
+            monorepo::patch_projects(&args.cargo_path, raw_args, *add_args)
+        }
+        Command::Add(add_args) => {
+            with_journal(&cargo_path, raw_args, || patch(&cargo_path, *add_args))
+        }
+        Command::Remove(remove_args) => with_journal(&cargo_path, raw_args, || {
+            remove::remove(&cargo_path, remove_args)
+        }),
+        Command::List => list::list(&cargo_path),
+        Command::Status => list::status(&cargo_path),
+        Command::Why(why_args) => why::why(&cargo_path, why_args),
+        Command::Undo(undo_args) => journal::undo(&cargo_path, undo_args.count),
+        Command::History => journal::history(&cargo_path),
+        Command::Check => check::check(&cargo_path),
+        Command::Lint(lint_args) => lint::lint(&cargo_path, lint_args),
+        Command::Exec(exec_args) => exec::exec(&cargo_path, exec_args),
+        Command::With(with_args) => with::with(&cargo_path, with_args),
+        Command::Profile(ProfileCommand::List) => profile::list(&cargo_path),
+        Command::Profile(ProfileCommand::Enable { name }) => {
+            with_journal(&cargo_path, raw_args, || {
+                profile::enable(&cargo_path, &name)
+            })
+        }
+        Command::Profile(ProfileCommand::Disable { name }) => {
+            with_journal(&cargo_path, raw_args, || {
+                profile::disable(&cargo_path, &name)
+            })
+        }
+        Command::Sync(sync_args) if sync_args.check => sync::sync(&cargo_path, sync_args),
+        Command::Sync(sync_args) => {
+            with_journal(&cargo_path, raw_args, || sync::sync(&cargo_path, sync_args))
+        }
+        Command::Migrate => migrate::migrate(&cargo_path, raw_args),
+    }
+    output::finish();
+    exit_code()
+}
+
+/// The exit code of the process, which is a failure if any error is logged
+fn exit_code() -> ExitCode {
+    match logger::has_errors() {
+        true => ExitCode::FAILURE,
+        false => ExitCode::SUCCESS,
+    }
+}
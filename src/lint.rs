@@ -0,0 +1,418 @@
+//! To check the committed patches against a policy, such as in CI
+//!
+//! The policy is read from `.dependencies-patch-policy.toml` in the cargo project, or the file
+//! given by `--policy`:
+//!
+//! ```toml
+//! # Forbid the patches to local paths, which only exist on the machine of the author
+//! deny-path = true
+//! # Forbid the git patches following a branch, which aren't reproducible
+//! deny-branch = true
+//! # The hosts or the owners the git patches may point to
+//! allowed-git = ["github.com/rust-lang", "git.corp.example"]
+//! # The maximum number of the patches
+//! max-patches = 5
+//! # Every patch must record why it exists by `--reason`
+//! require-reason = true
+//! ```
+
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+use toml_edit::ImDocument;
+
+use crate::{
+    arg_parse::LintArgs,
+    manifest::{read_patches, PatchEntry, PatchLocation},
+    output::{is_json, record_data},
+};
+
+/// The name of the policy file in the cargo project
+const POLICY_FILE: &str = ".dependencies-patch-policy.toml";
+
+/// The policy of the patches, where every rule is disabled defaultly
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Policy {
+    /// Forbid the patches to local paths
+    #[serde(default)]
+    deny_path: bool,
+    /// Forbid the git patches following a branch
+    #[serde(default)]
+    deny_branch: bool,
+    /// The hosts, such as `github.com`, or the owners, such as `github.com/rust-lang`,
+    /// the git patches may point to
+    allowed_git: Option<Vec<String>>,
+    /// The maximum number of the patches
+    max_patches: Option<usize>,
+    /// Every patch must record its reason in the metadata
+    #[serde(default)]
+    require_reason: bool,
+}
+
+/// A violation of the policy
+#[derive(Debug, Clone, Serialize)]
+struct Violation {
+    /// The file where the patch is in, relative to the cargo project
+    file: &'static str,
+    /// The line of the violation in the file, starting from 1
+    line: Option<usize>,
+    source: String,
+    key: String,
+    /// The rule violated, which is the key in the policy file
+    rule: &'static str,
+    message: String,
+}
+
+/// Read the policy file
+fn read_policy(cargo_path: &String, args: &LintArgs) -> Result<Policy, String> {
+    let path = match &args.policy {
+        Some(policy) => policy.clone(),
+        None => format!("{}/{}", cargo_path, POLICY_FILE),
+    };
+    let content =
+        fs::read_to_string(&path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    toml::from_str(&content).map_err(|err| format!("Failed to parse {}: {}", path, err))
+}
+
+/// The manifests with the spans of their items, to find the lines of the patches
+struct SpannedFiles {
+    files: Vec<(PatchLocation, String, ImDocument<String>)>,
+}
+
+impl SpannedFiles {
+    fn read(cargo_path: &String) -> Self {
+        let mut files = Vec::new();
+        for location in [PatchLocation::Manifest, PatchLocation::CargoConfig] {
+            let Ok(content) = fs::read_to_string(location.path(cargo_path)) else {
+                continue;
+            };
+            if let Ok(document) = ImDocument::parse(content.clone()) {
+                files.push((location, content, document));
+            }
+        }
+        Self { files }
+    }
+
+    /// Get the line of the field in the patch, or the line of the patch if the field isn't given
+    fn line(&self, entry: &PatchEntry, field: Option<&str>) -> Option<usize> {
+        let (_, content, document) = self
+            .files
+            .iter()
+            .find(|(location, _, _)| *location == entry.location)?;
+        let (key, item) = document
+            .get("patch")
+            .and_then(|patch| patch.get(&entry.source))
+            .and_then(|source| source.as_table_like())
+            .and_then(|source| source.get_key_value(&entry.key))?;
+        let field_span = field
+            .and_then(|field| item.as_table_like()?.get_key_value(field))
+            .and_then(|(field, _)| field.span());
+        let span = field_span.or(item.span()).or(key.span())?;
+        Some(content[..span.start].matches('\n').count() + 1)
+    }
+}
+
+/// Get the host and the owner of the git url, such as `github.com` and `rust-lang`
+fn git_host_owner(url: &str) -> (String, Option<String>) {
+    let url = url.strip_prefix("git+").unwrap_or(url);
+    // `https://github.com/owner/repo` or `git@github.com:owner/repo`
+    let (authority, path) = match url.split_once("://") {
+        Some((_, rest)) => rest.split_once('/').unwrap_or((rest, "")),
+        None => url.split_once(':').unwrap_or((url, "")),
+    };
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    let host = host.split(':').next().unwrap_or(host).to_lowercase();
+    let owner = path
+        .split('/')
+        .find(|part| !part.is_empty())
+        .map(|owner| owner.to_lowercase());
+    (host, owner)
+}
+
+/// Check whether the git url is allowed by the host or owner list
+fn is_allowed_git(url: &str, allowed: &[String]) -> bool {
+    let (host, owner) = git_host_owner(url);
+    allowed.iter().any(|allowed| {
+        let allowed = allowed.trim_end_matches('/').to_lowercase();
+        match allowed.split_once('/') {
+            Some((allowed_host, allowed_owner)) => {
+                allowed_host == host && owner.as_deref() == Some(allowed_owner)
+            }
+            None => allowed == host,
+        }
+    })
+}
+
+/// Check the patches against the policy
+fn check_policy(policy: &Policy, entries: &[PatchEntry], files: &SpannedFiles) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let mut violate = |entry: &PatchEntry, field: Option<&str>, rule, message: String| {
+        violations.push(Violation {
+            file: entry.location.file_name(),
+            line: files.line(entry, field),
+            source: entry.source.clone(),
+            key: entry.key.clone(),
+            rule,
+            message,
+        });
+    };
+    for (index, entry) in entries.iter().enumerate() {
+        if policy.deny_path && entry.kind() == "path" {
+            violate(
+                entry,
+                Some("path"),
+                "deny-path",
+                "Patches to local paths are not allowed".to_string(),
+            );
+        }
+        if policy.deny_branch && entry.table.contains_key("branch") {
+            violate(
+                entry,
+                Some("branch"),
+                "deny-branch",
+                "Git patches following a branch are not allowed, use `rev` or `tag` instead"
+                    .to_string(),
+            );
+        }
+        if let (Some(allowed), Some(git)) = (
+            &policy.allowed_git,
+            entry.table.get("git").and_then(|git| git.as_str()),
+        ) {
+            if !is_allowed_git(git, allowed) {
+                violate(
+                    entry,
+                    Some("git"),
+                    "allowed-git",
+                    format!(
+                        "The git url {} is not from the allowed hosts or owners",
+                        git
+                    ),
+                );
+            }
+        }
+        if policy
+            .max_patches
+            .is_some_and(|max_patches| index == max_patches)
+        {
+            violate(
+                entry,
+                None,
+                "max-patches",
+                format!(
+                    "There are {} patches, more than the maximum {}",
+                    entries.len(),
+                    policy.max_patches.unwrap()
+                ),
+            );
+        }
+        if policy.require_reason
+            && entry
+                .provenance
+                .as_ref()
+                .is_none_or(|provenance| provenance.reason.is_none())
+        {
+            violate(
+                entry,
+                None,
+                "require-reason",
+                "The reason of the patch is not recorded, add it by `--reason`".to_string(),
+            );
+        }
+    }
+    violations
+}
+
+/// Check the patches against the policy file, where every violation fails the command
+pub(crate) fn lint(cargo_path: &String, args: LintArgs) {
+    let policy = match read_policy(cargo_path, &args) {
+        Ok(policy) => policy,
+        Err(mes) => {
+            error_log!(code = "policy-failed", "{}", mes);
+            return;
+        }
+    };
+    let entries = match read_patches(cargo_path) {
+        Ok(entries) => entries,
+        Err(mes) => {
+            error_log!(code = "manifest-failed", "{}", mes);
+            return;
+        }
+    };
+    let violations = check_policy(&policy, &entries, &SpannedFiles::read(cargo_path));
+    if is_json() {
+        record_data(&violations);
+    } else {
+        for violation in &violations {
+            let position = match violation.line {
+                Some(line) => format!("{}:{}", violation.file, line),
+                None => violation.file.to_string(),
+            };
+            println!(
+                "{}: {}.{}: {} [{}]",
+                position, violation.source, violation.key, violation.message, violation.rule
+            );
+        }
+    }
+    if violations.is_empty() {
+        info_log!("All the {} patches follow the policy", entries.len());
+    } else {
+        error_log!(
+            code = "policy-violated",
+            "{} violations of the policy are found",
+            violations.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_policy_reports_the_lines_of_the_violations() {
+        let dir =
+            std::env::temp_dir().join(format!("dependencies-patch-lint-{}", std::process::id()));
+        fs::create_dir_all(dir.join(".cargo")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            r#"[package]
+name = "app"
+version = "0.1.0"
+
+[package.metadata.dependencies-patch.patches.crates-io.log]
+reason = "fix the logger"
+
+[patch.crates-io]
+log = { path = "../log" }
+serde = { git = "https://github.com/serde-rs/serde.git", branch = "fix" }
+
+[patch.crates-io.rand]
+git = "https://github.com/rust-lang/rand.git"
+rev = "0123abc"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join(".cargo/config.toml"),
+            "[patch.crates-io.time]\npath = \"../time\"\n",
+        )
+        .unwrap();
+        let cargo_path = dir.to_string_lossy().to_string();
+        let entries = read_patches(&cargo_path).unwrap();
+        let files = SpannedFiles::read(&cargo_path);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let check = |max_patches| {
+            let policy = Policy {
+                deny_path: true,
+                deny_branch: true,
+                allowed_git: Some(vec!["github.com/rust-lang".to_string()]),
+                max_patches: Some(max_patches),
+                require_reason: true,
+            };
+            check_policy(&policy, &entries, &files)
+                .into_iter()
+                .map(|violation| {
+                    (
+                        violation.file,
+                        violation.line.unwrap(),
+                        violation.key,
+                        violation.rule,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let violation = |file, line, key: &str, rule| (file, line, key.to_string(), rule);
+        let (manifest, config) = ("Cargo.toml", ".cargo/config.toml");
+        let violations = [
+            violation(manifest, 9, "log", "deny-path"),
+            violation(manifest, 12, "rand", "require-reason"),
+            violation(manifest, 10, "serde", "deny-branch"),
+            violation(manifest, 10, "serde", "allowed-git"),
+            violation(manifest, 10, "serde", "require-reason"),
+            violation(config, 2, "time", "deny-path"),
+            violation(config, 1, "time", "max-patches"),
+            violation(config, 1, "time", "require-reason"),
+        ];
+        // The fourth patch is beyond the maximum of 3
+        assert_eq!(check(3), violations);
+        // The maximum of 4 allows all the 4 patches
+        let mut allowed = violations.to_vec();
+        allowed.remove(6);
+        assert_eq!(check(4), allowed);
+        assert!(check(2)
+            .iter()
+            .any(|(_, _, key, rule)| key == "serde" && *rule == "max-patches"));
+    }
+
+    fn host_owner(host: &str, owner: Option<&str>) -> (String, Option<String>) {
+        (host.to_string(), owner.map(str::to_string))
+    }
+
+    #[test]
+    fn git_host_owner_of_urls() {
+        let cases = [
+            (
+                "https://github.com/rust-lang/log.git",
+                host_owner("github.com", Some("rust-lang")),
+            ),
+            (
+                "https://github.com/rust-lang//log.git",
+                host_owner("github.com", Some("rust-lang")),
+            ),
+            (
+                "git+https://GitHub.com/Rust-Lang/log",
+                host_owner("github.com", Some("rust-lang")),
+            ),
+            (
+                "ssh://git@git.corp.example:2222/team/log.git",
+                host_owner("git.corp.example", Some("team")),
+            ),
+            (
+                "git@gitlab.com:owner/log.git",
+                host_owner("gitlab.com", Some("owner")),
+            ),
+            (
+                "https://git.corp.example",
+                host_owner("git.corp.example", None),
+            ),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(git_host_owner(url), expected, "{}", url);
+        }
+    }
+
+    #[test]
+    fn is_allowed_git_by_host_or_owner() {
+        let allowed = [
+            "github.com/rust-lang/".to_string(),
+            "git.corp.example".to_string(),
+        ];
+        assert!(is_allowed_git(
+            "https://github.com/rust-lang/log.git",
+            &allowed
+        ));
+        assert!(is_allowed_git(
+            "https://github.com/Rust-Lang//log.git",
+            &allowed
+        ));
+        assert!(is_allowed_git(
+            "ssh://git@git.corp.example/any/log.git",
+            &allowed
+        ));
+        assert!(!is_allowed_git(
+            "https://github.com/someone/log.git",
+            &allowed
+        ));
+        assert!(!is_allowed_git(
+            "https://github.com.evil.example/rust-lang/log.git",
+            &allowed
+        ));
+        assert!(!is_allowed_git(
+            "https://gitlab.com/rust-lang/log.git",
+            &allowed
+        ));
+        assert!(!is_allowed_git("https://github.com/rust-lang/log.git", &[]));
+    }
+}
@@ -0,0 +1,171 @@
+//! List the patches and show their status
+
+use semver::{Version, VersionReq};
+use serde::Serialize;
+
+use crate::{
+    cargo_parse::{read_cargo_lock, CargoLock},
+    manifest::{read_patches, PatchEntry, PatchLocation},
+    output::{is_json, record_data},
+};
+
+/// List the patches in the Cargo.toml
+pub(crate) fn list(cargo_path: &String) {
+    let entries = match read_patches(cargo_path) {
+        Ok(entries) => entries,
+        Err(mes) => {
+            error_log!(code = "manifest-failed", "{}", mes);
+            return;
+        }
+    };
+    if is_json() {
+        record_data(&entries);
+        return;
+    }
+    if entries.is_empty() {
+        info_log!("There is no patch in the Cargo.toml");
+        return;
+    }
+    for entry in entries {
+        println!("{}", describe(&entry));
+    }
+}
+
+/// Describe a patch, such as `crates-io.log (package log) -> git https://github.com/rust-lang//log.git`
+fn describe(entry: &PatchEntry) -> String {
+    let package = if entry.package != entry.key {
+        format!(" (package {})", entry.package)
+    } else {
+        String::new()
+    };
+    let location = match entry.location {
+        PatchLocation::Manifest => String::new(),
+        location => format!(" [{}]", location.file_name()),
+    };
+    let mut description = format!(
+        "{}.{}{} -> {} {}{}",
+        entry.source,
+        entry.key,
+        package,
+        entry.kind(),
+        entry.target(),
+        location
+    );
+    // The provenance is shown below the patch
+    for line in entry
+        .provenance
+        .iter()
+        .flat_map(|provenance| provenance.lines())
+    {
+        description.push_str(&format!("\n    {}", line));
+    }
+    description
+}
+
+/// The status of a patch in the Cargo.lock
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PatchStatus {
+    #[serde(flatten)]
+    pub entry: PatchEntry,
+    /// `used`, `unused` or `not-locked`
+    pub status: &'static str,
+    /// The version locked from the patch
+    pub locked_version: Option<String>,
+}
+
+/// Check whether the locked version matches the `version` field of the patch if it is given,
+/// which tells the patches for the several versions of a package apart
+fn matches_version(entry: &PatchEntry, version: Option<&str>) -> bool {
+    let Some(req) = entry.table.get("version").and_then(|req| req.as_str()) else {
+        return true;
+    };
+    match (
+        VersionReq::parse(req),
+        version.map(Version::parse).and_then(Result::ok),
+    ) {
+        (Ok(req), Some(version)) => req.matches(&version),
+        _ => false,
+    }
+}
+
+/// Check whether the patch is used by the Cargo.lock
+pub(crate) fn check_status(entry: &PatchEntry, cargo_lock: &CargoLock) -> PatchStatus {
+    let status = |status, locked_version| PatchStatus {
+        entry: entry.clone(),
+        status,
+        locked_version,
+    };
+    // Cargo records the patches which are not used by any dependency
+    if cargo_lock
+        .patch
+        .unused
+        .iter()
+        .any(|package| package.name == entry.package)
+    {
+        return status("unused", None);
+    }
+    let locked = cargo_lock.package.iter().find(|package| {
+        if package.name != entry.package {
+            return false;
+        }
+        match entry.kind() {
+            "git" => {
+                let git = entry.table["git"].as_str().unwrap_or_default();
+                package
+                    .source
+                    .as_ref()
+                    .is_some_and(|source| source.starts_with(&format!("git+{}", git)))
+                    && matches_version(entry, package.version.as_deref())
+            }
+            "path" => package.source.is_none(),
+            _ => {
+                entry.table.contains_key("version")
+                    && matches_version(entry, package.version.as_deref())
+            }
+        }
+    });
+    match locked {
+        Some(package) => status("used", package.version.clone()),
+        None => status("not-locked", None),
+    }
+}
+
+/// Show whether each patch is used by the Cargo.lock
+pub(crate) fn status(cargo_path: &String) {
+    let entries = match read_patches(cargo_path) {
+        Ok(entries) => entries,
+        Err(mes) => {
+            error_log!(code = "manifest-failed", "{}", mes);
+            return;
+        }
+    };
+    let cargo_lock = match read_cargo_lock(cargo_path) {
+        Ok(cargo_lock) => cargo_lock,
+        Err(mes) => {
+            error_log!(code = "lockfile-failed", "{}", mes);
+            return;
+        }
+    };
+    let statuses = entries
+        .iter()
+        .map(|entry| check_status(entry, &cargo_lock))
+        .collect::<Vec<_>>();
+    if is_json() {
+        record_data(&statuses);
+        return;
+    }
+    if statuses.is_empty() {
+        info_log!("There is no patch in the Cargo.toml");
+        return;
+    }
+    for status in &statuses {
+        let state = match (status.status, &status.locked_version) {
+            ("used", Some(version)) => format!("used ({})", version),
+            (state, _) => state.to_string(),
+        };
+        println!("{}: {}", describe(&status.entry), state);
+    }
+    if statuses.iter().any(|status| status.status == "not-locked") {
+        warn_log!("Some patches are not in the Cargo.lock yet, run `cargo update --workspace` to update it");
+    }
+}
@@ -1,23 +1,156 @@
 //! To do logging
+//!
+//! All the log lines are written to stderr, so stdout stays clean for the data output.
 
-use color_print::cprintln;
+use std::{
+    io::IsTerminal,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use clap::ValueEnum;
+use color_print::cformat;
+
+use crate::output::record_log;
+
+/// The level of a log message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    /// The details such as the lockfile lookups and the resolved sources, shown by `-v`
+    Debug,
+    /// The cargo commands run and their outputs, shown by `-vv`
+    Trace,
+}
+
+/// When to colour the log lines
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ColorChoice {
+    /// Colour the log lines if stderr is a terminal and `NO_COLOR` is not set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// The most verbose level to show, which is `Info` defaultly
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// The number of the errors logged in the run
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// The message of the newest error logged in the run
+static LAST_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+/// Whether any error has been logged in the run, such as to roll back the changes
+pub(crate) fn has_errors() -> bool {
+    error_count() > 0
+}
+
+/// The number of the errors logged in the run, to know whether a part of the run fails
+pub(crate) fn error_count() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+/// The message of the newest error logged in the run, to tell why a part of the run fails
+pub(crate) fn last_error() -> Option<String> {
+    LAST_ERROR.lock().unwrap().clone()
+}
+
+/// Set the verbosity and the colour of the logger
+///
+/// # Arguments
+///
+/// - `quiet`: Only show the errors
+///
+/// - `verbose`: The number of `-v`, where one shows the debug messages and two show the traces
+///
+/// - `color`: When to colour the log lines
+pub(crate) fn init_logger(quiet: bool, verbose: u8, color: ColorChoice) {
+    let level = match (quiet, verbose) {
+        (true, _) => LogLevel::Error,
+        (false, 0) => LogLevel::Info,
+        (false, 1) => LogLevel::Debug,
+        (false, _) => LogLevel::Trace,
+    };
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+    let color = match color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && std::io::stderr().is_terminal()
+        }
+    };
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+/// Print a log line to stderr if its level is enabled
+fn print_log(level: LogLevel, message: &str) {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    let color = COLOR.load(Ordering::Relaxed);
+    let tag = match (level, color) {
+        (LogLevel::Error, true) => cformat!("<red><bold>[ERROR]</bold></red>"),
+        (LogLevel::Warn, true) => cformat!("<yellow><bold>[WARN]</bold></yellow>"),
+        (LogLevel::Info, true) => cformat!("<green><bold>[INFO]</bold></green>"),
+        (LogLevel::Debug, true) => cformat!("<blue><bold>[DEBUG]</bold></blue>"),
+        (LogLevel::Trace, true) => cformat!("<dim>[TRACE]</dim>"),
+        (LogLevel::Error, false) => "[ERROR]".to_string(),
+        (LogLevel::Warn, false) => "[WARN]".to_string(),
+        (LogLevel::Info, false) => "[INFO]".to_string(),
+        (LogLevel::Debug, false) => "[DEBUG]".to_string(),
+        (LogLevel::Trace, false) => "[TRACE]".to_string(),
+    };
+    eprintln!("{} {}", tag, message);
+}
 
 #[allow(dead_code)]
 pub(crate) fn patch_info(args: std::fmt::Arguments) {
     let info = format!("{}", args);
-    cprintln!("<green><bold>{}</bold></green> {}", "[INFO]", info);
+    if record_log(LogLevel::Info, None, &info) {
+        return;
+    }
+    print_log(LogLevel::Info, &info);
 }
 
+/// Log an error with a machine-readable code, which is shown in the JSON output
 #[allow(dead_code)]
-pub(crate) fn patch_error(args: std::fmt::Arguments) {
+pub(crate) fn patch_error(code: &str, args: std::fmt::Arguments) {
     let error = format!("{}", args);
-    cprintln!("<red><bold>{}</bold></red> {}", "[ERROR]", error);
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+    *LAST_ERROR.lock().unwrap() = Some(error.clone());
+    if record_log(LogLevel::Error, Some(code), &error) {
+        return;
+    }
+    print_log(LogLevel::Error, &error);
 }
 
 #[allow(dead_code)]
 pub(crate) fn patch_warn(args: std::fmt::Arguments) {
     let warn = format!("{}", args);
-    cprintln!("<yellow><bold>{}</bold></yellow> {}", "[WARN]", warn);
+    if record_log(LogLevel::Warn, None, &warn) {
+        return;
+    }
+    print_log(LogLevel::Warn, &warn);
+}
+
+/// The debug messages are never recorded in the JSON output, but still printed to stderr
+#[allow(dead_code)]
+pub(crate) fn patch_debug(args: std::fmt::Arguments) {
+    print_log(LogLevel::Debug, &format!("{}", args));
+}
+
+#[allow(dead_code)]
+pub(crate) fn patch_trace(args: std::fmt::Arguments) {
+    print_log(LogLevel::Trace, &format!("{}", args));
 }
 
 #[allow(unused)]
@@ -27,9 +160,11 @@ macro_rules! info_log{
     };
 }
 
+/// Log an error, whose machine-readable code is given first, such as
+/// `error_log!(code = "package-not-found", "The package {} is not found", name)`
 macro_rules! error_log {
-    ($($arg:tt)*) => {
-        crate::logger::patch_error(format_args!($($arg)*));
+    (code = $code:expr, $($arg:tt)*) => {
+        crate::logger::patch_error($code, format_args!($($arg)*));
     };
 }
 
@@ -38,3 +173,15 @@ macro_rules! warn_log {
         crate::logger::patch_warn(format_args!($($arg)*));
     };
 }
+
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        crate::logger::patch_debug(format_args!($($arg)*));
+    };
+}
+
+macro_rules! trace_log {
+    ($($arg:tt)*) => {
+        crate::logger::patch_trace(format_args!($($arg)*));
+    };
+}
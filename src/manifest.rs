@@ -0,0 +1,409 @@
+//! To read and edit the patch tables in the Cargo.toml and the `.cargo/config.toml`
+
+use std::{fs, path::Path, sync::Mutex};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use toml::Table;
+use toml_edit::DocumentMut;
+
+use crate::{
+    provenance::{read_provenances, Provenance},
+    transaction::write_file,
+};
+
+/// The file where the patches are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum PatchLocation {
+    /// The Cargo.toml of the project
+    #[default]
+    Manifest,
+    /// The `.cargo/config.toml` of the project, which keeps the Cargo.toml untouched
+    CargoConfig,
+}
+
+impl PatchLocation {
+    /// The path of the file relative to the cargo project
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            PatchLocation::Manifest => "Cargo.toml",
+            PatchLocation::CargoConfig => ".cargo/config.toml",
+        }
+    }
+
+    /// The path of the file in the cargo project
+    pub fn path(&self, cargo_path: &String) -> String {
+        format!("{}/{}", cargo_path, self.file_name())
+    }
+}
+
+/// A patch in the Cargo.toml, such as `[patch.crates-io.log]`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct PatchEntry {
+    /// The key of the source table, such as `crates-io` or a git url
+    pub source: String,
+    /// The key of the package table
+    pub key: String,
+    /// The real name of the patched package, which is the `package` field or the key
+    pub package: String,
+    /// The contents of the package table
+    pub table: Table,
+    /// The file where the patch is in
+    pub location: PatchLocation,
+    /// Why the patch exists, which is recorded in the metadata of the Cargo.toml
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+}
+
+impl PatchEntry {
+    /// The kind of the patch, which is `git`, `path` or `registry`
+    pub fn kind(&self) -> &'static str {
+        if self.table.contains_key("git") {
+            "git"
+        } else if self.table.contains_key("path") {
+            "path"
+        } else {
+            "registry"
+        }
+    }
+
+    /// Describe where the patch points to, such as `https://github.com/rust-lang//log.git tag=v0.4.22`
+    pub fn target(&self) -> String {
+        describe_target(&self.table)
+    }
+}
+
+/// Describe where the patch table points to, such as `https://github.com/rust-lang//log.git tag=v0.4.22`
+pub(crate) fn describe_target(table: &Table) -> String {
+    let mut target = Vec::new();
+    for key in ["git", "path", "registry"] {
+        if let Some(value) = table.get(key).and_then(|value| value.as_str()) {
+            target.push(value.to_string());
+        }
+    }
+    for key in ["rev", "tag", "branch", "version"] {
+        if let Some(value) = table.get(key).and_then(|value| value.as_str()) {
+            target.push(format!("{}={}", key, value));
+        }
+    }
+    target.join(" ")
+}
+
+/// The patch tables captured instead of being written, see [`capture_patches`]
+static CAPTURED: Mutex<Option<Table>> = Mutex::new(None);
+
+/// Run the command with the appended patch tables captured rather than written into any file
+///
+/// # Return
+///
+/// The captured tables in the format of `{ patch = { <source> = { <key> = { .. } } } }`
+pub(crate) fn capture_patches(command: impl FnOnce()) -> Table {
+    *CAPTURED.lock().unwrap() = Some(Table::new());
+    command();
+    CAPTURED.lock().unwrap().take().unwrap_or_default()
+}
+
+/// Whether the patch tables are captured rather than written
+pub(crate) fn is_capturing() -> bool {
+    CAPTURED.lock().unwrap().is_some()
+}
+
+/// Read all the patches in the Cargo.toml and the `.cargo/config.toml` of the target project
+pub(crate) fn read_patches(cargo_path: &String) -> Result<Vec<PatchEntry>, String> {
+    let mut entries = Vec::new();
+    for location in [PatchLocation::Manifest, PatchLocation::CargoConfig] {
+        let path = location.path(cargo_path);
+        // The `.cargo/config.toml` is optional
+        if location == PatchLocation::CargoConfig && !Path::new(&path).exists() {
+            continue;
+        }
+        let mut file_entries = Vec::new();
+        let content = read_patch_file(&path, location, &mut file_entries)?;
+        // The provenances are recorded in the same file as the patches
+        for (source, key, provenance) in read_provenances(&content, location)? {
+            if let Some(entry) = file_entries
+                .iter_mut()
+                .find(|entry| entry.source == source && entry.key == key)
+            {
+                entry.provenance = Some(provenance);
+            }
+        }
+        entries.extend(file_entries);
+    }
+    Ok(entries)
+}
+
+/// Read the patches in one file, and return the parsed file
+fn read_patch_file(
+    path: &String,
+    location: PatchLocation,
+    entries: &mut Vec<PatchEntry>,
+) -> Result<Table, String> {
+    let content =
+        fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let content: Table =
+        toml::from_str(&content).map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+    let Some(patch_table) = content.get("patch").and_then(|patch| patch.as_table()) else {
+        return Ok(content);
+    };
+    for (source, source_table) in patch_table {
+        let Some(source_table) = source_table.as_table() else {
+            continue;
+        };
+        for (key, table) in source_table {
+            let Some(table) = table.as_table() else {
+                continue;
+            };
+            entries.push(PatchEntry {
+                source: source.clone(),
+                key: key.clone(),
+                package: table
+                    .get("package")
+                    .and_then(|package| package.as_str())
+                    .unwrap_or(key)
+                    .to_string(),
+                table: table.clone(),
+                location,
+                provenance: None,
+            });
+        }
+    }
+    Ok(content)
+}
+
+/// Read the patches in a patch set, which has the `[patch.<source>.<key>]` tables as in the Cargo.toml
+///
+/// # Return
+///
+/// - Ok(patches): The source, the key and the table of every patch
+/// - Err(mes): The error message
+pub(crate) fn read_patch_set(path: &String) -> Result<Vec<(String, String, Table)>, String> {
+    let content =
+        fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let content: Table =
+        toml::from_str(&content).map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+    let sources = content
+        .get("patch")
+        .and_then(|patch| patch.as_table())
+        .ok_or(format!("There is no patch in {}", path))?;
+    let mut patches = Vec::new();
+    for (source, packages) in sources {
+        let packages = packages.as_table().ok_or(format!(
+            "The patch for {} in {} is not a table",
+            source, path
+        ))?;
+        for (key, table) in packages {
+            let table = table.as_table().ok_or(format!(
+                "The patch {}.{} in {} is not a table",
+                source, key, path
+            ))?;
+            patches.push((source.clone(), key.clone(), table.clone()));
+        }
+    }
+    Ok(patches)
+}
+
+/// Append the patch tables to the file of the given location in the transaction
+///
+/// The `.cargo/config.toml` is created if it doesn't exist.
+pub(crate) fn append_patch(
+    cargo_path: &String,
+    location: PatchLocation,
+    toml_table: &Table,
+) -> Result<(), String> {
+    if let Some(captured) = CAPTURED.lock().unwrap().as_mut() {
+        merge_tables(captured, toml_table);
+        return Ok(());
+    }
+    let path = location.path(cargo_path);
+    // The `.cargo/config.toml` may not exist yet
+    let content = match Path::new(&path).exists() {
+        true => {
+            fs::read_to_string(&path).map_err(|err| format!("Failed to read {}: {}", path, err))?
+        }
+        false => String::new(),
+    };
+    let content = format!("{}\n{}", content, toml::to_string(toml_table).unwrap());
+    write_file(Path::new(&path), &content)
+}
+
+/// Merge the tables recursively, where the values of the other table win
+fn merge_tables(table: &mut Table, other: &Table) {
+    for (key, value) in other {
+        match (table.get_mut(key), value) {
+            (Some(toml::Value::Table(table)), toml::Value::Table(other)) => {
+                merge_tables(table, other)
+            }
+            _ => {
+                table.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Remove a patch from the file of the given location in the transaction, keeping the format of the other parts
+///
+/// The source table and the patch table are removed too if they become empty.
+pub(crate) fn remove_patch(
+    cargo_path: &String,
+    location: PatchLocation,
+    source: &str,
+    key: &str,
+) -> Result<(), String> {
+    let cargo_toml_path = location.path(cargo_path);
+    let cargo_toml = fs::read_to_string(&cargo_toml_path)
+        .map_err(|err| format!("Failed to read {}: {}", cargo_toml_path, err))?;
+    let mut document = cargo_toml
+        .parse::<DocumentMut>()
+        .map_err(|err| format!("Failed to parse {}: {}", cargo_toml_path, err))?;
+
+    let patch_table = document
+        .get_mut("patch")
+        .and_then(|patch| patch.as_table_like_mut())
+        .ok_or(format!("There is no patch in {}", cargo_toml_path))?;
+    let source_table = patch_table
+        .get_mut(source)
+        .and_then(|source_table| source_table.as_table_like_mut())
+        .ok_or(format!(
+            "There is no patch for {} in {}",
+            source, cargo_toml_path
+        ))?;
+    if source_table.remove(key).is_none() {
+        return Err(format!(
+            "The patch {} for {} is not found in {}",
+            key, source, cargo_toml_path
+        ));
+    }
+    if source_table.is_empty() {
+        patch_table.remove(source);
+    }
+    if patch_table.is_empty() {
+        document.remove("patch");
+    }
+    write_file(Path::new(&cargo_toml_path), &document.to_string())
+}
+
+/// Set a patch in the file of the given location in the transaction, keeping the format of the other parts
+///
+/// The patch replaces the existing one with the same key, and the file is created if it doesn't exist.
+pub(crate) fn set_patch(
+    cargo_path: &String,
+    location: PatchLocation,
+    source: &str,
+    key: &str,
+    table: &Table,
+) -> Result<(), String> {
+    let path = location.path(cargo_path);
+    let content = match Path::new(&path).exists() {
+        true => {
+            fs::read_to_string(&path).map_err(|err| format!("Failed to read {}: {}", path, err))?
+        }
+        false => String::new(),
+    };
+    let mut document = content
+        .parse::<DocumentMut>()
+        .map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+    let entry = to_edit_table(table);
+
+    // The parent tables are implicit, so only `[patch.<source>.<key>]` is written
+    let patch_table = document
+        .entry("patch")
+        .or_insert_with(implicit_table)
+        .as_table_like_mut()
+        .ok_or(format!("The patch in {} is not a table", path))?;
+    let source_table = patch_table
+        .entry(source)
+        .or_insert(implicit_table())
+        .as_table_like_mut()
+        .ok_or(format!(
+            "The patch for {} in {} is not a table",
+            source, path
+        ))?;
+    source_table.insert(key, toml_edit::Item::Table(entry));
+    write_file(Path::new(&path), &document.to_string())
+}
+
+/// Create an implicit table, whose header is not written unless it has its own keys
+pub(crate) fn implicit_table() -> toml_edit::Item {
+    let mut table = toml_edit::Table::new();
+    table.set_implicit(true);
+    toml_edit::Item::Table(table)
+}
+
+/// Convert a serializable value into a table to be inserted into a document
+pub(crate) fn to_edit_table<T: Serialize>(value: &T) -> toml_edit::Table {
+    toml::to_string(value)
+        .unwrap()
+        .parse::<DocumentMut>()
+        .unwrap()
+        .as_table()
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_patches_with_provenance_next_to_the_patches() {
+        let dir = std::env::temp_dir().join(format!(
+            "dependencies-patch-manifest-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(dir.join(".cargo")).unwrap();
+        fs::write(
+            dir.join("Cargo.toml"),
+            r#"[package]
+name = "demo"
+version = "0.1.0"
+
+[package.metadata.dependencies-patch.patches.crates-io.log]
+reason = "fix the logger"
+
+[package.metadata.dependencies-patch.patches.crates-io.strsim]
+reason = "only for the patch in the Cargo.toml"
+
+[patch.crates-io.log]
+path = "../log"
+"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join(".cargo/config.toml"),
+            r#"[dependencies-patch.patches.crates-io.serde]
+reason = "try the fork"
+
+[patch.crates-io.serde]
+git = "https://github.com/serde-rs/serde.git"
+
+[patch.crates-io.strsim]
+path = "../strsim"
+"#,
+        )
+        .unwrap();
+
+        let entries = read_patches(&dir.to_string_lossy().to_string()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let reason = |key: &str| {
+            let entry = entries.iter().find(|entry| entry.key == key).unwrap();
+            (
+                entry.location,
+                entry
+                    .provenance
+                    .as_ref()
+                    .and_then(|provenance| provenance.reason.clone()),
+            )
+        };
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            reason("log"),
+            (PatchLocation::Manifest, Some("fix the logger".to_string()))
+        );
+        assert_eq!(
+            reason("serde"),
+            (PatchLocation::CargoConfig, Some("try the fork".to_string()))
+        );
+        assert_eq!(reason("strsim"), (PatchLocation::CargoConfig, None));
+    }
+}
@@ -0,0 +1,587 @@
+//! To migrate the legacy overrides into patches
+//!
+//! The deprecated `[replace]` table of the Cargo.toml, such as `"foo:0.1.0" = { git = "..." }`,
+//! and the `paths = [...]` overrides of the `.cargo/config.toml` or `.cargo/config` of the project
+//! are converted into the `[patch.<source>]` entries, where the sources are found in the
+//! Cargo.lock. The migrated overrides are removed, and the ones which can't be expressed as
+//! patches are reported and kept.
+//!
+//! The migration is written in one transaction as the other commands. It is recorded in the
+//! journal as an irreversible operation, since the journal only knows the patch entries.
+
+use std::{fs, path::Path};
+
+use serde::Serialize;
+use toml::Table;
+use toml_edit::DocumentMut;
+
+use crate::{
+    cargo_parse::{read_cargo_lock, CargoLock},
+    config::config,
+    journal::with_irreversible_journal,
+    logger::error_count,
+    manifest::{read_patches, set_patch},
+    output::{is_json, record_data},
+    registry::registry_source_key,
+    transaction::write_file,
+};
+
+/// The cargo config files of the project which may have the `paths` overrides
+const CARGO_CONFIGS: [&str; 2] = [".cargo/config.toml", ".cargo/config"];
+
+/// The result of migrating a legacy override
+#[derive(Debug, Clone, Serialize)]
+struct Migration {
+    /// `replace` or `paths`
+    kind: &'static str,
+    /// The key of the `[replace]` table, or the overriding path
+    from: String,
+    /// The keys of the patches written for it, such as `crates-io.foo`
+    patches: Vec<String>,
+    /// Why it can't be expressed as a patch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+/// A patch to be written for a legacy override
+struct PlannedPatch {
+    source: String,
+    key: String,
+    table: Table,
+}
+
+/// Get the key of the patch table for a source in the Cargo.lock, such as `crates-io` or a git url
+fn patch_source(source: &str) -> String {
+    match source.strip_prefix("git+") {
+        Some(url) => url.split(['?', '#']).next().unwrap_or(url).to_string(),
+        None => registry_source_key(source),
+    }
+}
+
+/// Find the sources of the locked package, where the version is optional
+fn locked_sources(cargo_lock: &CargoLock, name: &str, version: Option<&str>) -> Vec<String> {
+    let mut sources = Vec::new();
+    for locked in &cargo_lock.package {
+        let Some(source) = &locked.source else {
+            continue;
+        };
+        if locked.name != name
+            || version.is_some_and(|version| locked.version.as_deref() != Some(version))
+        {
+            continue;
+        }
+        let source = patch_source(source);
+        if !sources.contains(&source) {
+            sources.push(source);
+        }
+    }
+    sources
+}
+
+/// Parse the key of the `[replace]` table, which is a package id spec such as `foo:0.1.0`
+/// or `https://github.com/rust-lang/crates.io-index#foo@0.1.0`
+///
+/// # Return
+///
+/// The source url if it is given, the name and the version if it is given
+fn parse_replace_key(spec: &str) -> (Option<&str>, &str, Option<&str>) {
+    let (url, package) = match spec.rsplit_once('#') {
+        Some((url, package)) => (Some(url), package),
+        None => (None, spec),
+    };
+    match package.split_once([':', '@']) {
+        Some((name, version)) => (url, name, Some(version)),
+        None => (url, package, None),
+    }
+}
+
+/// Plan the patch for an entry of the `[replace]` table
+fn plan_replace(
+    cargo_lock: &CargoLock,
+    spec: &str,
+    value: &toml::Value,
+) -> Result<PlannedPatch, String> {
+    let table = value
+        .as_table()
+        .ok_or("The replacement is not a table".to_string())?;
+    if !table.contains_key("git") && !table.contains_key("path") {
+        return Err("Only the git and path replacements can be patches".to_string());
+    }
+    let (url, name, version) = parse_replace_key(spec);
+    let source = match url {
+        Some(url) => registry_source_key(url),
+        None => {
+            let sources = locked_sources(cargo_lock, name, version);
+            match sources.as_slice() {
+                [source] => source.clone(),
+                [] => {
+                    return Err(format!(
+                        "The package {} is not found in the Cargo.lock",
+                        spec
+                    ))
+                }
+                _ => {
+                    return Err(format!(
+                        "The package {} is locked from several sources: {}",
+                        spec,
+                        sources.join(", ")
+                    ))
+                }
+            }
+        }
+    };
+    Ok(PlannedPatch {
+        source,
+        key: name.to_string(),
+        table: table.clone(),
+    })
+}
+
+/// Plan the patches for a `paths` override, one for each source of the package in the Cargo.lock
+fn plan_path(
+    cargo_path: &String,
+    cargo_lock: &CargoLock,
+    path: &str,
+) -> Result<Vec<PlannedPatch>, String> {
+    // The paths are relative to the directory which has the `.cargo` directory
+    let manifest_path = Path::new(cargo_path).join(path).join("Cargo.toml");
+    let manifest = fs::read_to_string(&manifest_path)
+        .map_err(|err| format!("Failed to read {}: {}", manifest_path.display(), err))?;
+    let manifest: Table = toml::from_str(&manifest)
+        .map_err(|err| format!("Failed to parse {}: {}", manifest_path.display(), err))?;
+    let name = manifest
+        .get("package")
+        .and_then(|package| package.get("name"))
+        .and_then(|name| name.as_str())
+        .ok_or(format!(
+            "There is no package in {}",
+            manifest_path.display()
+        ))?;
+    let sources = locked_sources(cargo_lock, name, None);
+    if sources.is_empty() {
+        return Err(format!(
+            "The package {} is not found in the Cargo.lock",
+            name
+        ));
+    }
+    Ok(sources
+        .into_iter()
+        .map(|source| PlannedPatch {
+            source,
+            key: name.to_string(),
+            table: Table::from_iter([("path".to_string(), toml::Value::String(path.to_string()))]),
+        })
+        .collect())
+}
+
+/// Read a TOML file of the project as a document if it exists
+fn read_document(path: &Path) -> Result<Option<DocumentMut>, String> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    content
+        .parse::<DocumentMut>()
+        .map(Some)
+        .map_err(|err| format!("Failed to parse {}: {}", path.display(), err))
+}
+
+/// Write the planned patches of a legacy override unless they conflict with the existing ones
+///
+/// # Arguments
+///
+/// - `kind`: `replace` or `paths`
+///
+/// - `from`: The key of the `[replace]` table, or the overriding path
+///
+/// - `planned`: The planned patches, or why the override can't be expressed as patches
+///
+/// # Return
+///
+/// - Ok(migrated): Whether the patches are written, where the result is pushed to `migrations`
+/// - Err(mes): The error message of reading or writing the files
+fn migrate_override(
+    cargo_path: &String,
+    kind: &'static str,
+    from: &str,
+    planned: Result<Vec<PlannedPatch>, String>,
+    migrations: &mut Vec<Migration>,
+) -> Result<bool, String> {
+    let entries = read_patches(cargo_path)?;
+    let conflict = |patches: &[PlannedPatch]| {
+        patches.iter().find_map(|patch| {
+            let entry = entries
+                .iter()
+                .find(|entry| entry.source == patch.source && entry.key == patch.key)?;
+            Some(format!(
+                "The package is patched already by {}.{} to {}",
+                entry.source,
+                entry.key,
+                entry.target()
+            ))
+        })
+    };
+    let planned = planned.and_then(|patches| match conflict(&patches) {
+        Some(reason) => Err(reason),
+        None => Ok(patches),
+    });
+    let mut migration = Migration {
+        kind,
+        from: from.to_string(),
+        patches: Vec::new(),
+        reason: None,
+    };
+    let migrated = match planned {
+        Ok(patches) => {
+            for patch in &patches {
+                set_patch(
+                    cargo_path,
+                    config().patch_location(),
+                    &patch.source,
+                    &patch.key,
+                    &patch.table,
+                )?;
+                migration
+                    .patches
+                    .push(format!("{}.{}", patch.source, patch.key));
+            }
+            true
+        }
+        Err(reason) => {
+            migration.reason = Some(reason);
+            false
+        }
+    };
+    migrations.push(migration);
+    Ok(migrated)
+}
+
+/// Migrate the `[replace]` table of the Cargo.toml, and remove the migrated entries
+fn migrate_replace(
+    cargo_path: &String,
+    cargo_lock: &CargoLock,
+    migrations: &mut Vec<Migration>,
+) -> Result<(), String> {
+    let cargo_toml_path = Path::new(cargo_path).join("Cargo.toml");
+    let cargo_toml = fs::read_to_string(&cargo_toml_path)
+        .map_err(|err| format!("Failed to read {}: {}", cargo_toml_path.display(), err))?;
+    let cargo_toml: Table = toml::from_str(&cargo_toml)
+        .map_err(|err| format!("Failed to parse {}: {}", cargo_toml_path.display(), err))?;
+    let Some(replace) = cargo_toml.get("replace") else {
+        return Ok(());
+    };
+    let replace = replace.as_table().ok_or(format!(
+        "The replace in {} is not a table",
+        cargo_toml_path.display()
+    ))?;
+    let mut migrated = Vec::new();
+    for (spec, value) in replace {
+        let planned = plan_replace(cargo_lock, spec, value).map(|patch| vec![patch]);
+        if migrate_override(cargo_path, "replace", spec, planned, migrations)? {
+            migrated.push(spec.clone());
+        }
+    }
+    if migrated.is_empty() {
+        return Ok(());
+    }
+    // The patches may be written into the Cargo.toml, so it is read again
+    let mut document = read_document(&cargo_toml_path)?.unwrap_or_default();
+    if let Some(replace) = document
+        .get_mut("replace")
+        .and_then(|replace| replace.as_table_like_mut())
+    {
+        for spec in &migrated {
+            replace.remove(spec);
+        }
+        if replace.is_empty() {
+            document.remove("replace");
+        }
+    }
+    write_file(&cargo_toml_path, &document.to_string())
+}
+
+/// Migrate the `paths` overrides of a cargo config file, and remove the migrated paths
+fn migrate_paths(
+    cargo_path: &String,
+    cargo_lock: &CargoLock,
+    config_name: &str,
+    migrations: &mut Vec<Migration>,
+) -> Result<(), String> {
+    let config_path = Path::new(cargo_path).join(config_name);
+    let Some(document) = read_document(&config_path)? else {
+        return Ok(());
+    };
+    let Some(paths) = document.get("paths") else {
+        return Ok(());
+    };
+    let paths = paths
+        .as_array()
+        .ok_or(format!(
+            "The paths in {} is not an array",
+            config_path.display()
+        ))?
+        .iter()
+        .filter_map(|path| path.as_str().map(String::from))
+        .collect::<Vec<_>>();
+    let mut migrated = Vec::new();
+    for path in paths {
+        let planned = plan_path(cargo_path, cargo_lock, &path);
+        if migrate_override(cargo_path, "paths", &path, planned, migrations)? {
+            migrated.push(path);
+        }
+    }
+    if migrated.is_empty() {
+        return Ok(());
+    }
+    // The patches may be written into the cargo config, so it is read again
+    let mut document = read_document(&config_path)?.unwrap_or_default();
+    if let Some(paths) = document
+        .get_mut("paths")
+        .and_then(|paths| paths.as_array_mut())
+    {
+        paths.retain(|path| {
+            !path
+                .as_str()
+                .is_some_and(|path| migrated.iter().any(|migrated| migrated == path))
+        });
+        paths.fmt();
+        if paths.is_empty() {
+            document.remove("paths");
+        }
+    }
+    write_file(&config_path, &document.to_string())
+}
+
+/// Migrate all the legacy overrides of the project in a transaction
+pub(crate) fn migrate(cargo_path: &String, args: Vec<String>) {
+    let cargo_lock = match read_cargo_lock(cargo_path) {
+        Ok(cargo_lock) => cargo_lock,
+        Err(mes) => {
+            error_log!(code = "lockfile-failed", "{}", mes);
+            return;
+        }
+    };
+    let errors = error_count();
+    let mut migrations = Vec::new();
+    let reason = "it also removes the legacy overrides";
+    with_irreversible_journal(cargo_path, args, reason, || {
+        if let Err(mes) = migrate_replace(cargo_path, &cargo_lock, &mut migrations) {
+            error_log!(code = "migrate-failed", "{}", mes);
+            return;
+        }
+        for config_name in CARGO_CONFIGS {
+            if let Err(mes) = migrate_paths(cargo_path, &cargo_lock, config_name, &mut migrations) {
+                error_log!(code = "migrate-failed", "{}", mes);
+                return;
+            }
+        }
+    });
+    // Everything is rolled back on the errors
+    if error_count() > errors {
+        return;
+    }
+
+    for migration in &migrations {
+        match &migration.reason {
+            Some(reason) => {
+                warn_log!(
+                    "The {} override {} can't be migrated: {}",
+                    migration.kind,
+                    migration.from,
+                    reason
+                );
+            }
+            None => {
+                info_log!(
+                    "Migrated the {} override {} to {}",
+                    migration.kind,
+                    migration.from,
+                    migration.patches.join(", ")
+                );
+            }
+        }
+    }
+    if is_json() {
+        record_data(&migrations);
+    } else if migrations.is_empty() {
+        info_log!("There is no legacy override to migrate");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CRATES_IO_INDEX: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+    fn cargo_lock(packages: &[(&str, &str, &str)]) -> CargoLock {
+        let packages = packages
+            .iter()
+            .map(|(name, version, source)| {
+                format!(
+                    "[[package]]\nname = \"{}\"\nversion = \"{}\"\nsource = \"{}\"\n",
+                    name, version, source
+                )
+            })
+            .collect::<Vec<_>>();
+        toml::from_str(&packages.join("\n")).unwrap()
+    }
+
+    #[test]
+    fn parse_replace_key_of_package_id_specs() {
+        let cases = [
+            ("foo:0.1.0", (None, "foo", Some("0.1.0"))),
+            ("foo@0.1.0", (None, "foo", Some("0.1.0"))),
+            ("foo", (None, "foo", None)),
+            (
+                "https://github.com/rust-lang/crates.io-index#foo@0.1.0",
+                (
+                    Some("https://github.com/rust-lang/crates.io-index"),
+                    "foo",
+                    Some("0.1.0"),
+                ),
+            ),
+            (
+                "https://github.com/rust-lang/crates.io-index#foo:0.1.0",
+                (
+                    Some("https://github.com/rust-lang/crates.io-index"),
+                    "foo",
+                    Some("0.1.0"),
+                ),
+            ),
+            (
+                "https://example.com/index#foo",
+                (Some("https://example.com/index"), "foo", None),
+            ),
+        ];
+        for (spec, expected) in cases {
+            assert_eq!(parse_replace_key(spec), expected, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn plan_replace_finds_the_source_in_the_lockfile() {
+        let cargo_lock = cargo_lock(&[
+            ("foo", "0.1.0", CRATES_IO_INDEX),
+            ("bar", "1.0.0", CRATES_IO_INDEX),
+            ("bar", "1.0.0", "git+https://github.com/me/bar.git#0123abc"),
+            (
+                "qux",
+                "0.3.0",
+                "git+https://github.com/me/qux.git?branch=main#0123abc",
+            ),
+        ]);
+        let git =
+            toml::Value::Table(toml::from_str("git = \"https://github.com/me/foo.git\"").unwrap());
+        let plan = |spec: &str, value: &toml::Value| {
+            plan_replace(&cargo_lock, spec, value)
+                .map(|patch| (patch.source, patch.key, patch.table))
+        };
+        let planned = |source: &str, key: &str| {
+            Ok((
+                source.to_string(),
+                key.to_string(),
+                git.as_table().unwrap().clone(),
+            ))
+        };
+
+        assert_eq!(plan("foo:0.1.0", &git), planned("crates-io", "foo"));
+        assert_eq!(plan("foo", &git), planned("crates-io", "foo"));
+        assert_eq!(
+            plan("qux:0.3.0", &git),
+            planned("https://github.com/me/qux.git", "qux")
+        );
+        assert_eq!(
+            plan(
+                "https://github.com/rust-lang/crates.io-index#bar@1.0.0",
+                &git
+            ),
+            planned("crates-io", "bar")
+        );
+        assert_eq!(
+            plan("foo:0.2.0", &git).unwrap_err(),
+            "The package foo:0.2.0 is not found in the Cargo.lock"
+        );
+        assert_eq!(
+            plan("bar:1.0.0", &git).unwrap_err(),
+            "The package bar:1.0.0 is locked from several sources: crates-io, https://github.com/me/bar.git"
+        );
+        let registry = toml::Value::Table(toml::from_str("version = \"0.1.1\"").unwrap());
+        assert_eq!(
+            plan("foo:0.1.0", &registry).unwrap_err(),
+            "Only the git and path replacements can be patches"
+        );
+        assert_eq!(
+            plan("foo:0.1.0", &toml::Value::from("0.1.1")).unwrap_err(),
+            "The replacement is not a table"
+        );
+    }
+
+    #[test]
+    fn migrate_paths_into_patches() {
+        let dir =
+            std::env::temp_dir().join(format!("dependencies-patch-migrate-{}", std::process::id()));
+        fs::create_dir_all(dir.join("app/.cargo")).unwrap();
+        fs::create_dir_all(dir.join("bar")).unwrap();
+        fs::write(
+            dir.join("app/Cargo.toml"),
+            "[package]\nname = \"app\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("app/.cargo/config.toml"),
+            "paths = [\"../bar\", \"../missing\"]\n\n[build]\njobs = 2\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("bar/Cargo.toml"),
+            "[package]\nname = \"bar\"\nversion = \"1.0.0\"\n",
+        )
+        .unwrap();
+        let cargo_lock = cargo_lock(&[
+            ("bar", "1.0.0", CRATES_IO_INDEX),
+            ("bar", "0.9.0", "git+https://github.com/me/bar.git#0123abc"),
+        ]);
+        let cargo_path = dir.join("app").to_string_lossy().to_string();
+        let mut migrations = Vec::new();
+        let result = migrate_paths(
+            &cargo_path,
+            &cargo_lock,
+            ".cargo/config.toml",
+            &mut migrations,
+        );
+        let cargo_toml = fs::read_to_string(dir.join("app/Cargo.toml")).unwrap();
+        let cargo_config = fs::read_to_string(dir.join("app/.cargo/config.toml")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        result.unwrap();
+        let patches = toml::from_str::<Table>(&cargo_toml).unwrap()["patch"].clone();
+        assert_eq!(
+            patches,
+            toml::from_str::<toml::Value>(
+                r#"
+crates-io = { bar = { path = "../bar" } }
+"https://github.com/me/bar.git" = { bar = { path = "../bar" } }
+"#
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            cargo_config,
+            "paths = [\"../missing\"]\n\n[build]\njobs = 2\n"
+        );
+        assert_eq!(migrations.len(), 2);
+        assert_eq!(
+            migrations[0].patches,
+            ["crates-io.bar", "https://github.com/me/bar.git.bar"]
+        );
+        assert!(migrations[0].reason.is_none());
+        assert_eq!(migrations[1].from, "../missing");
+        assert!(migrations[1]
+            .reason
+            .as_ref()
+            .unwrap()
+            .starts_with("Failed to read"));
+    }
+}
@@ -0,0 +1,298 @@
+//! To apply one patch set across the independent cargo projects of a monorepo
+//!
+//! The projects are given by several `-c`, or found under the directory given by `--recursive`.
+//! Only the projects whose Cargo.lock contains the patched packages are patched, each in its
+//! own transaction, so a failing project doesn't affect the others. The relative local paths of
+//! the patches are relative to every project, as for a single project, so a local crate shared
+//! by the projects at different depths is given by its absolute path.
+//! The config is loaded once, from the directory searched or the first project, because the
+//! specs are parsed with it before any project is patched.
+
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use serde::Serialize;
+use toml::Table;
+
+use crate::{
+    arg_parse::AddArgs,
+    cargo_parse::{read_cargo_lock, resolve_package_name, workspace_manifests},
+    journal::with_journal,
+    logger::{error_count, last_error},
+    manifest::read_patches,
+    output::{is_json, record_data},
+    patch::{collect_specs, patch_specs},
+    spec::PatchSpec,
+};
+
+/// The result of patching a project
+#[derive(Debug, Clone, Serialize)]
+struct ProjectResult {
+    project: String,
+    /// `patched`, `skipped` or `failed`
+    status: &'static str,
+    /// The patches added or changed, such as `crates-io.log`
+    patches: Vec<String>,
+    /// Why the project is skipped or failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+/// Find all the cargo projects under the directory
+///
+/// The `target` directories and the hidden directories are skipped, and so are the members of
+/// the workspaces found, which are patched through their workspace roots.
+pub(crate) fn discover_projects(dir: &str) -> Result<Vec<String>, String> {
+    fn walk(dir: &Path, manifests: &mut Vec<(PathBuf, bool)>) {
+        let manifest_path = dir.join("Cargo.toml");
+        if let Ok(manifest) = fs::read_to_string(&manifest_path) {
+            let is_workspace = toml::from_str::<Table>(&manifest)
+                .is_ok_and(|manifest| manifest.contains_key("workspace"));
+            manifests.push((manifest_path, is_workspace));
+        }
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        let mut entries = entries.flatten().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if file_name == "target" || file_name.starts_with('.') {
+                continue;
+            }
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                walk(&entry.path(), manifests);
+            }
+        }
+    }
+
+    if !Path::new(dir).is_dir() {
+        return Err(format!("{} is not a directory", dir));
+    }
+    let mut manifests = Vec::new();
+    walk(Path::new(dir), &mut manifests);
+    let mut members = Vec::new();
+    for (manifest_path, _) in manifests.iter().filter(|(_, is_workspace)| *is_workspace) {
+        let root = manifest_path
+            .parent()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        for (member, _) in workspace_manifests(&root)?.into_iter().skip(1) {
+            members.push(member);
+        }
+    }
+    let projects = manifests
+        .into_iter()
+        .filter(|(manifest_path, _)| !members.contains(manifest_path))
+        .map(|(manifest_path, _)| {
+            manifest_path
+                .parent()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    debug_log!("Found the cargo projects: {}", projects.join(", "));
+    Ok(projects)
+}
+
+/// Get the patch specs whose packages are in the Cargo.lock of the project, not as local paths
+fn used_specs(cargo_path: &String, specs: &[PatchSpec]) -> Result<Vec<PatchSpec>, String> {
+    let cargo_lock = read_cargo_lock(cargo_path)?;
+    Ok(specs
+        .iter()
+        .filter(|spec| {
+            let package = match spec.target.package() {
+                Some(package) => package.clone(),
+                None => resolve_package_name(cargo_path, &spec.name).unwrap_or(spec.name.clone()),
+            };
+            cargo_lock
+                .package
+                .iter()
+                .any(|locked| locked.name == package && locked.source.is_some())
+        })
+        .cloned()
+        .collect())
+}
+
+/// Get the path of the target relative to the base, where both are absolute
+pub(crate) fn relative_path(base: &Path, target: &Path) -> PathBuf {
+    let base = base.components().collect::<Vec<_>>();
+    let target = target.components().collect::<Vec<_>>();
+    let common = base
+        .iter()
+        .zip(&target)
+        .take_while(|(base, target)| base == target)
+        .count();
+    let mut path = PathBuf::new();
+    for _ in common..base.len() {
+        path.push(Component::ParentDir);
+    }
+    path.extend(&target[common..]);
+    path
+}
+
+/// Get the keys of the patches, such as `crates-io.log`, with their tables
+fn patch_keys(cargo_path: &String) -> Vec<(String, Table)> {
+    read_patches(cargo_path)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| (format!("{}.{}", entry.source, entry.key), entry.table))
+        .collect()
+}
+
+/// Patch every project which depends on the packages, and summarise the results
+///
+/// # Arguments
+///
+/// - `cargo_paths`: The paths of the cargo projects
+///
+/// - `raw_args`: The arguments of the command line, recorded in the journal of every project
+///
+/// - `args`: The arguments of the `add` command
+pub(crate) fn patch_projects(cargo_paths: &[String], raw_args: Vec<String>, args: AddArgs) {
+    let specs = match collect_specs(&args) {
+        Ok(specs) => specs,
+        Err(mes) => {
+            error_log!(code = "invalid-spec", "{}", mes);
+            return;
+        }
+    };
+    let mut results = Vec::new();
+    for cargo_path in cargo_paths {
+        let skipped = |reason: String| ProjectResult {
+            project: cargo_path.clone(),
+            status: "skipped",
+            patches: Vec::new(),
+            reason: Some(reason),
+        };
+        let specs = match used_specs(cargo_path, &specs) {
+            Ok(specs) if specs.is_empty() => {
+                results.push(skipped("none of the packages is used".to_string()));
+                continue;
+            }
+            Ok(specs) => specs,
+            Err(mes) => {
+                results.push(skipped(mes));
+                continue;
+            }
+        };
+        info_log!("Patch the project {}", cargo_path);
+        let errors = error_count();
+        let before = patch_keys(cargo_path);
+        with_journal(cargo_path, raw_args.clone(), || {
+            patch_specs(cargo_path, specs, &args)
+        });
+        let patches = patch_keys(cargo_path)
+            .into_iter()
+            .filter(|patch| !before.contains(patch))
+            .map(|(key, _)| key)
+            .collect();
+        let failed = error_count() > errors;
+        results.push(ProjectResult {
+            project: cargo_path.clone(),
+            status: match failed {
+                true => "failed",
+                false => "patched",
+            },
+            patches,
+            reason: failed.then(last_error).flatten(),
+        });
+    }
+    let count = |status| {
+        results
+            .iter()
+            .filter(|result| result.status == status)
+            .count()
+    };
+    info_log!(
+        "{} projects are patched, {} are skipped and {} failed",
+        count("patched"),
+        count("skipped"),
+        count("failed")
+    );
+    if is_json() {
+        record_data(&results);
+        return;
+    }
+    for result in &results {
+        let detail = match (result.status, &result.reason) {
+            (_, Some(reason)) => format!(" ({})", reason),
+            ("patched", None) => format!(" {}", result.patches.join(", ")),
+            _ => String::new(),
+        };
+        println!("{}: {}{}", result.project, result.status, detail);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write the files under the directory, creating the parent directories
+    fn write_files(dir: &Path, files: &[(&str, &str)]) {
+        for (path, content) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+    }
+
+    fn package(name: &str) -> String {
+        format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", name)
+    }
+
+    #[test]
+    fn discover_projects_skips_members_and_build_dirs() {
+        let dir = std::env::temp_dir().join(format!(
+            "dependencies-patch-monorepo-{}",
+            std::process::id()
+        ));
+        write_files(
+            &dir,
+            &[
+                (
+                    "services/Cargo.toml",
+                    "[workspace]\nmembers = [\"api\", \"web\"]\n",
+                ),
+                ("services/api/Cargo.toml", &package("api")),
+                ("services/web/Cargo.toml", &package("web")),
+                ("tools/Cargo.toml", &package("tools")),
+                ("tools/target/package/Cargo.toml", &package("tools")),
+                (".hidden/Cargo.toml", &package("hidden")),
+                ("docs/README.md", ""),
+            ],
+        );
+        let projects = discover_projects(&dir.to_string_lossy());
+        let missing = discover_projects(&dir.join("missing").to_string_lossy());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let expected =
+            ["services", "tools"].map(|project| dir.join(project).to_string_lossy().to_string());
+        assert_eq!(projects.unwrap(), expected);
+        assert!(missing.unwrap_err().ends_with("is not a directory"));
+    }
+
+    #[test]
+    fn relative_path_between_absolute_paths() {
+        let cases = [
+            ("/repo/services/api", "/repo/vendor/log", "../../vendor/log"),
+            ("/repo", "/repo/vendor/log", "vendor/log"),
+            ("/repo/tools", "/repo", ".."),
+            ("/repo", "/repo", ""),
+            ("/repo", "/other/log", "../other/log"),
+        ];
+        for (base, target, expected) in cases {
+            assert_eq!(
+                relative_path(Path::new(base), Path::new(target)),
+                PathBuf::from(expected),
+                "{} -> {}",
+                base,
+                target
+            );
+        }
+    }
+}
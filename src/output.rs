@@ -0,0 +1,159 @@
+//! To collect the results of a run and output them as a structured document
+
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use serde::Serialize;
+use toml::Table;
+
+use crate::logger::LogLevel;
+
+/// The output format of the tool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// Coloured log lines and human readable results
+    #[default]
+    Human,
+    /// One JSON document per run
+    Json,
+}
+
+/// A patch written into the Cargo.toml
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PatchRecord {
+    /// The key of the source table, such as `crates-io` or a git url
+    pub source: String,
+    /// The key of the package table
+    pub package: String,
+    /// The contents of the package table
+    pub table: Table,
+}
+
+/// An error with a machine-readable code
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ErrorRecord {
+    pub code: String,
+    pub message: String,
+}
+
+/// The document output in the JSON format
+#[derive(Debug, Default, Serialize)]
+struct Document {
+    /// The command which is run
+    command: String,
+    /// Whether the run succeeds without any error
+    success: bool,
+    /// The patches written into the Cargo.toml
+    patches: Vec<PatchRecord>,
+    /// The data produced by the command, such as the lockfile report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+    infos: Vec<String>,
+    warnings: Vec<String>,
+    errors: Vec<ErrorRecord>,
+}
+
+/// The output state of the run
+struct Output {
+    format: OutputFormat,
+    document: Document,
+    /// The patches written by the running transaction, which are only output once it commits
+    pending: Vec<PatchRecord>,
+}
+
+static OUTPUT: Mutex<Output> = Mutex::new(Output {
+    format: OutputFormat::Human,
+    document: Document {
+        command: String::new(),
+        success: true,
+        patches: Vec::new(),
+        data: None,
+        infos: Vec::new(),
+        warnings: Vec::new(),
+        errors: Vec::new(),
+    },
+    pending: Vec::new(),
+});
+
+/// Set the output format and the name of the command to run
+pub(crate) fn init_output(format: OutputFormat, command: &str) {
+    let mut output = OUTPUT.lock().unwrap();
+    output.format = format;
+    output.document.command = command.to_string();
+}
+
+/// Whether the results are output as a JSON document
+pub(crate) fn is_json() -> bool {
+    OUTPUT.lock().unwrap().format == OutputFormat::Json
+}
+
+/// Record a log message in the JSON document
+///
+/// # Return
+///
+/// - true: The message is recorded, so it should not be printed
+///
+/// - false: The output format is human, so it should be printed
+pub(crate) fn record_log(level: LogLevel, code: Option<&str>, message: &str) -> bool {
+    let mut output = OUTPUT.lock().unwrap();
+    if output.format != OutputFormat::Json {
+        return false;
+    }
+    let document = &mut output.document;
+    match level {
+        LogLevel::Error => {
+            document.success = false;
+            document.errors.push(ErrorRecord {
+                code: code.unwrap_or("error").to_string(),
+                message: message.to_string(),
+            });
+        }
+        LogLevel::Warn => document.warnings.push(message.to_string()),
+        LogLevel::Info | LogLevel::Debug | LogLevel::Trace => {
+            document.infos.push(message.to_string())
+        }
+    }
+    true
+}
+
+/// Record a patch written into the Cargo.toml
+///
+/// The patch is pending until the transaction commits, see [`commit_patches`], so the patches
+/// rolled back or only captured are never output.
+pub(crate) fn record_patch(source: &str, package: &str, table: &Table) {
+    let mut output = OUTPUT.lock().unwrap();
+    output.pending.push(PatchRecord {
+        source: source.to_string(),
+        package: package.to_string(),
+        table: table.clone(),
+    });
+}
+
+/// Output the pending patches, after the transaction which wrote them commits
+pub(crate) fn commit_patches() {
+    let mut output = OUTPUT.lock().unwrap();
+    let pending = std::mem::take(&mut output.pending);
+    output.document.patches.extend(pending);
+}
+
+/// Drop the pending patches, after the transaction which wrote them rolls back
+pub(crate) fn discard_patches() {
+    OUTPUT.lock().unwrap().pending.clear();
+}
+
+/// Record the data produced by the command
+pub(crate) fn record_data<T: Serialize>(data: &T) {
+    let mut output = OUTPUT.lock().unwrap();
+    output.document.data = Some(serde_json::to_value(data).unwrap());
+}
+
+/// Finish the run, which prints the JSON document if the output format is JSON
+pub(crate) fn finish() {
+    let output = OUTPUT.lock().unwrap();
+    if output.format == OutputFormat::Json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&output.document).unwrap()
+        );
+    }
+}
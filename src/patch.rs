@@ -1,41 +1,185 @@
-use std::fs;
+use std::{collections::BTreeSet, fs, path::Path};
 
 use toml::Table;
 
 use crate::{
-    arg_parse::Args,
-    cargo_parse::{pick_package, Dependency},
-    git_patch::{self, GitInfo, GitPatch},
-    index_patch::{self, IndexPatch},
+    arg_parse::AddArgs,
+    cargo_parse::{
+        compat_range, pick_family, pick_package, read_cargo_lock, resolve_package_name, Dependency,
+    },
+    config::{config, ConflictPolicy},
+    git_patch, index_patch,
+    manifest::{is_capturing, read_patches, remove_patch, PatchEntry},
+    output::{is_json, record_data},
     path_patch::{self, PathPatch},
+    provenance::{git_author, record_provenance, Provenance},
+    report::{diff_lock, render_report, snapshot_lock, update_lock, LockSnapshot},
+    spec::{parse_spec, read_batch, PatchSpec, PatchTarget},
+    transaction::utc_now,
 };
 
-/// Check whether the patch exists for the specific package
-fn check_patch_exist(
+/// Get the version of the existing patch, from its `version` field, the crate in its local path,
+/// or the package in the Cargo.lock which it resolves to
+fn patch_version(cargo_path: &String, entry: &PatchEntry) -> Option<String> {
+    if let Some(version) = entry
+        .table
+        .get("version")
+        .and_then(|version| version.as_str())
+    {
+        return Some(version.to_string());
+    }
+    if let Some(version) = entry
+        .table
+        .get("path")
+        .and_then(|path| path.as_str())
+        .and_then(|path| local_version(cargo_path, path))
+    {
+        return Some(version);
+    }
+    locked_version(cargo_path, entry)
+}
+
+/// Get the version of the package in the Cargo.lock which the git or path patch resolves to
+fn locked_version(cargo_path: &String, entry: &PatchEntry) -> Option<String> {
+    let git = entry.table.get("git").and_then(|git| git.as_str());
+    let versions = read_cargo_lock(cargo_path)
+        .ok()?
+        .package
+        .into_iter()
+        .filter(|package| {
+            package.name == entry.package
+                && match (git, &package.source) {
+                    (Some(git), Some(source)) => source.starts_with(&format!("git+{}", git)),
+                    (None, source) => entry.kind() == "path" && source.is_none(),
+                    _ => false,
+                }
+        })
+        .filter_map(|package| package.version)
+        .collect::<BTreeSet<_>>();
+    // The version is ambiguous if the patch resolves to several ones
+    match versions.len() {
+        1 => versions.into_iter().next(),
+        _ => None,
+    }
+}
+
+/// Get the version of the crate in the local path, which is relative to the cargo project
+pub(crate) fn local_version(cargo_path: &String, path: &str) -> Option<String> {
+    let manifest_path = Path::new(cargo_path).join(path).join("Cargo.toml");
+    let manifest: Table = toml::from_str(&fs::read_to_string(manifest_path).ok()?).ok()?;
+    let version = manifest.get("package")?.get("version")?.as_str()?;
+    Some(version.to_string())
+}
+
+/// Find the existing patch which the new patch conflicts with for the source
+///
+/// If the version of the new patch is known, only the patch of the same package with a
+/// compatible version conflicts, otherwise the patch with the same key conflicts. The patch
+/// whose version is unknown conflicts only if it has the same key.
+///
+/// # Return
+///
+/// - Ok(entry): The conflicting patch
+/// - Err(others): The patches of the package for the other versions, where nothing conflicts
+fn find_existing_patch(
     cargo_path: &String,
     package_name: &String,
-    package_dependency: &Dependency,
-) -> bool {
-    let cargo_toml_path = format!("{}/Cargo.toml", cargo_path);
-    let cargo_toml = fs::read_to_string(cargo_toml_path).unwrap();
-    let cargo_toml: Table = toml::from_str(&cargo_toml).unwrap();
-    if !cargo_toml.contains_key("patch") {
-        return false;
-    }
-    let patch_table = cargo_toml.get("patch").unwrap().as_table().unwrap();
-    if patch_table.contains_key(package_name) {
-        return true;
-    }
-
-    match package_dependency {
-        Dependency::Git(git) => patch_table.contains_key(git),
-        Dependency::Registry(_) => {
-            // TODO: only support crates-io now
-            let registry_table = patch_table.get("crates-io").unwrap().as_table().unwrap();
-            registry_table.contains_key(package_name)
+    real_package_name: &String,
+    source: &str,
+    version: Option<&str>,
+) -> Result<PatchEntry, Vec<PatchEntry>> {
+    let entries = read_patches(cargo_path)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|entry| entry.source == source)
+        .collect::<Vec<_>>();
+    let Some(range) = version.and_then(compat_range) else {
+        return entries
+            .into_iter()
+            .find(|entry| entry.key == *package_name)
+            .ok_or(Vec::new());
+    };
+    let (compatible, others) = entries
+        .into_iter()
+        .filter(|entry| entry.key == *package_name || entry.package == *real_package_name)
+        .partition::<Vec<_>, _>(|entry| {
+            match patch_version(cargo_path, entry).and_then(|version| compat_range(&version)) {
+                Some(entry_range) => entry_range == range,
+                None => entry.key == *package_name,
+            }
+        });
+    compatible.into_iter().next().ok_or(others)
+}
+
+/// Get the key of the patch for a version of the package, such as `rand07` for `0.7.3`
+fn version_key(package_name: &str, version: &str) -> Option<String> {
+    Some(format!("{}{}", package_name, compat_range(version)?))
+}
+
+/// Get the real package name for the `package` field if the key of the patch differs from it
+pub(crate) fn package_alias(key: &str, real_package_name: &str) -> Option<String> {
+    (key != real_package_name).then(|| real_package_name.to_string())
+}
+
+/// Get the package id spec of the locked package which the patch replaces, such as `rand@0.7.3`
+///
+/// The locked version is told apart by the version of the patch, or by the version range in its
+/// key such as `rand07`. The bare name is used if the version can't be told.
+fn patched_spec(cargo_path: &String, entry: &PatchEntry, before: &LockSnapshot) -> String {
+    let versions = before
+        .get(&entry.package)
+        .into_iter()
+        .flatten()
+        .filter_map(|package| package.version.clone())
+        .collect::<BTreeSet<_>>();
+    let range = patch_version(cargo_path, entry)
+        .and_then(|version| compat_range(&version))
+        .or_else(|| {
+            let suffix = entry.key.strip_prefix(entry.package.as_str())?;
+            (!suffix.is_empty()).then(|| suffix.to_string())
+        });
+    let version = match range {
+        Some(range) => versions
+            .iter()
+            .find(|version| compat_range(version).as_ref() == Some(&range)),
+        None if versions.len() == 1 => versions.first(),
+        None => None,
+    };
+    match version {
+        Some(version) => format!("{}@{}", entry.package, version),
+        None => entry.package.clone(),
+    }
+}
+
+/// Get the package id specs of the locked packages whose patches are added or changed since
+/// `before`, such as a patch replaced by another path, git revision or version
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project
+///
+/// - `before`: The patches before the change
+///
+/// - `before_lock`: The snapshot of the Cargo.lock before the change
+fn changed_packages(
+    cargo_path: &String,
+    before: &[PatchEntry],
+    before_lock: &LockSnapshot,
+) -> Vec<String> {
+    let mut packages = Vec::new();
+    for entry in read_patches(cargo_path).unwrap_or_default() {
+        let unchanged = before.iter().any(|old| {
+            old.location == entry.location
+                && old.source == entry.source
+                && old.key == entry.key
+                && old.table == entry.table
+        });
+        let spec = patched_spec(cargo_path, &entry, before_lock);
+        if !unchanged && !packages.contains(&spec) {
+            packages.push(spec);
         }
-        _ => false,
     }
+    packages
 }
 
 /// To generate the patch table by Cargo.lock
@@ -48,90 +192,401 @@ fn check_patch_exist(
 ///
 /// - `real_package_name`: The real package name to be patched
 ///
+/// - `version`: The version or the requirement of the patch if it is known, which tells the
+///   patches for the several semver incompatible versions of the package apart
+///
 /// # Return
 ///
-/// - Some((patch_table, package_index)):
+/// - Some((patch_table, package_index, key)):
 ///    - patch_table: The patch table to be written into the Cargo.toml
 ///    - package_index: The URL or registry name of the package set in the Cargo.lock
+///    - key: The key of the patch, which is the package name, or the package name with the
+///      version range such as `rand07` if the package is patched for another version already
 pub(crate) fn gen_patch_table(
     cargo_path: &String,
     package_name: &String,
     real_package_name: &String,
-) -> Option<(Table, String)> {
+    version: Option<&str>,
+) -> Option<(Table, String, String)> {
     // If the package has been renamed, the `package` field in the patch should be used
     // because it is the real package name.
-    let package = match pick_package(cargo_path, real_package_name) {
+    let package = match pick_package(cargo_path, real_package_name, version) {
         Ok(package) => package,
         Err(mes) => {
-            error_log!("{}", mes);
+            error_log!(code = "package-not-found", "{}", mes);
             return None;
         }
     };
     let package_dependency = package.parse_dependency();
+    debug_log!(
+        "The source of package {} is resolved as {:?}",
+        real_package_name,
+        package_dependency
+    );
+
+    let package_index = match package_dependency {
+        Dependency::Git(git) => git.to_string(),
+        Dependency::Registry(registry) => registry,
+        _ => {
+            error_log!(
+                code = "path-dependency",
+                "The package specified is a path dependency, which can't be patched!"
+            );
+            return None;
+        }
+    };
 
     // But when do patch, we should use the original package name whether it has been renamed or not.
-    if check_patch_exist(cargo_path, package_name, &package_dependency) {
-        error_log!(
-            "The patch for package {} already exists! Do nothing!",
-            package_name
-        );
-        return None;
+    // The captured patches are passed to cargo as its config, which takes precedence over the manifest.
+    let mut key = package_name.clone();
+    match find_existing_patch(
+        cargo_path,
+        package_name,
+        real_package_name,
+        &package_index,
+        version,
+    ) {
+        Ok(existing) if !is_capturing() => match config().on_conflict() {
+            ConflictPolicy::Error => {
+                error_log!(
+                    code = "patch-exists",
+                    "The patch for package {} already exists! Do nothing!",
+                    package_name
+                );
+                return None;
+            }
+            ConflictPolicy::Skip => {
+                warn_log!(
+                    "The patch for package {} already exists, skip it",
+                    package_name
+                );
+                return None;
+            }
+            ConflictPolicy::Replace => {
+                if let Err(mes) = remove_patch(
+                    cargo_path,
+                    existing.location,
+                    &existing.source,
+                    &existing.key,
+                ) {
+                    error_log!(code = "write-failed", "{}", mes);
+                    return None;
+                }
+                info_log!(
+                    "Replace the existing patch for package {} in {}",
+                    package_name,
+                    existing.location.file_name()
+                );
+                key = existing.key;
+            }
+        },
+        Ok(_) => {}
+        Err(others) if others.iter().any(|entry| entry.key == *package_name) => {
+            // Cargo tells the patches for the several versions apart by their keys
+            key = version_key(package_name, version?)?;
+            info_log!(
+                "The key {} is taken by the patch of package {} for another version, add the patch {} for {} instead",
+                package_name,
+                real_package_name,
+                key,
+                version?
+            );
+        }
+        Err(_) => {}
     }
 
     let mut cargo_toml: Table = Table::new();
+    let mut patch_table = Table::new();
+    patch_table.insert(package_index.clone(), toml::Value::Table(Table::new()));
+    cargo_toml.insert(String::from("patch"), toml::Value::Table(patch_table));
+    Some((cargo_toml, package_index, key))
+}
 
-    cargo_toml.insert(String::from("patch"), toml::Value::Table(Table::new()));
-
-    let patch_table = cargo_toml.get_mut("patch").unwrap().as_table_mut().unwrap();
+/// Patch the packages according to the arguments
+///
+/// The patches are given by the patch specs, the batch file, or the flags of the `add` command.
+/// If `--report` is given, the impact of the patches on the Cargo.lock file is reported.
+pub(crate) fn patch(cargo_path: &String, args: AddArgs) {
+    let specs = match collect_specs(&args) {
+        Ok(specs) => specs,
+        Err(mes) => {
+            error_log!(code = "invalid-spec", "{}", mes);
+            return;
+        }
+    };
+    patch_specs(cargo_path, specs, &args);
+}
 
-    match package_dependency {
-        Dependency::Git(git) => {
-            patch_table.insert(git.to_string(), toml::Value::Table(Table::new()));
-            Some((cargo_toml, git.to_string()))
+/// Patch the packages by the collected patch specs, with the other arguments of the `add` command
+pub(crate) fn patch_specs(cargo_path: &String, specs: Vec<PatchSpec>, args: &AddArgs) {
+    if !args.report {
+        apply_patches(cargo_path, specs, args);
+        return;
+    }
+    let report_format = args.report_format;
+    let before = match snapshot_lock(cargo_path) {
+        Ok(snapshot) => snapshot,
+        Err(mes) => {
+            error_log!(code = "lockfile-failed", "{}", mes);
+            return;
         }
-        Dependency::Registry(_) => {
-            // TODO: only support crates-io now
-            patch_table.insert("crates-io".to_string(), toml::Value::Table(Table::new()));
-            Some((cargo_toml, "crates-io".to_string()))
+    };
+    let patched = read_patches(cargo_path).unwrap_or_default();
+    apply_patches(cargo_path, specs, args);
+    let packages = changed_packages(cargo_path, &patched, &before);
+    if packages.is_empty() {
+        // Nothing is patched
+        return;
+    }
+    match update_lock(cargo_path, &packages) {
+        Ok(after) => {
+            let changes = diff_lock(&before, &after);
+            if is_json() {
+                record_data(&changes);
+            } else {
+                println!("{}", render_report(&changes, report_format));
+            }
         }
-        _ => {
-            error_log!("The package specified is a path dependency, which can't be patched!");
-            None
+        Err(mes) => {
+            error_log!(code = "lockfile-failed", "{}", mes);
         }
     }
 }
 
-pub(crate) fn patch(args: Args) {
-    let cargo_path = args.cargo_path.unwrap();
-    match args.patch_type.as_str() {
-        "git" => {
-            let mut git_info = GitInfo::None;
-            if let Some(commit) = &args.commit {
-                git_info = GitInfo::Commit(commit.to_string());
-            } else if let Some(tag) = &args.tag {
-                git_info = GitInfo::Tag(tag.to_string());
-            } else if let Some(branch) = &args.branch {
-                git_info = GitInfo::Branch(branch.to_string());
+/// Collect the patches given by the patch specs, the batch file and the flags
+pub(crate) fn collect_specs(args: &AddArgs) -> Result<Vec<PatchSpec>, String> {
+    let mut specs = Vec::new();
+    for spec in &args.specs {
+        specs.push(parse_spec(spec).map_err(|err| err.to_string())?);
+    }
+    if let Some(batch) = &args.batch {
+        specs.extend(read_batch(batch)?);
+    }
+    if args.package_name.is_some() {
+        specs.push(PatchSpec::from_args(args));
+    }
+    if args.latest_matching {
+        for spec in &mut specs {
+            if let PatchTarget::Index(index_patch) = &mut spec.target {
+                index_patch.set_latest_matching(true);
             }
-            let git_patch = GitPatch::new(
-                args.git_repo.unwrap(),
-                args.real_package_name,
-                args.package_version,
-                git_info,
-            );
-            git_patch::do_git_patch(&cargo_path, &args.package_name, git_patch);
         }
-        // "path" => {
-        //     // patch(&args.cargo_path, &args.package_name, &args.patch_path.unwrap());
-        // }
-        "registry" => {
-            let index_patch =
-                IndexPatch::new(args.real_package_name, args.package_version.unwrap());
-            index_patch::do_index_patch(&cargo_path, &args.package_name, &index_patch);
+    }
+    if args.family
+        && specs
+            .iter()
+            .any(|spec| matches!(spec.target, PatchTarget::Index(_)))
+    {
+        return Err("The family can't be used with registry patch!".to_string());
+    }
+    Ok(specs)
+}
+
+/// Apply all the patches, and record the provenance of the written patches if it is given
+fn apply_patches(cargo_path: &String, specs: Vec<PatchSpec>, args: &AddArgs) {
+    let provenance = (args.reason.is_some() || args.issue.is_some() || args.expires.is_some())
+        .then(|| Provenance {
+            reason: args.reason.clone(),
+            issue: args.issue.clone(),
+            expires: args.expires.clone(),
+            ..Default::default()
+        });
+    apply_specs(cargo_path, specs, args.family, provenance, args.comment);
+}
+
+/// Apply the patch specs, and record the provenance of the written patches
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project, where the Cargo.toml file is in
+///
+/// - `specs`: The patch specs to be applied
+///
+/// - `family`: Also patch the packages of the same family
+///
+/// - `provenance`: The provenance to be recorded, where the author and the date are filled in
+///
+/// - `comment`: Also write the provenance as a TOML comment above the patch
+pub(crate) fn apply_specs(
+    cargo_path: &String,
+    specs: Vec<PatchSpec>,
+    family: bool,
+    provenance: Option<Provenance>,
+    comment: bool,
+) {
+    let before = read_patches(cargo_path).unwrap_or_default();
+    for spec in specs {
+        apply_patch(cargo_path, spec, family);
+    }
+    let Some(provenance) = provenance else {
+        return;
+    };
+    let provenance = Provenance {
+        author: git_author(cargo_path),
+        date: Some(utc_now()[..10].to_string()),
+        ..provenance
+    };
+    // The patches which are added or replaced
+    let written = read_patches(cargo_path)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|entry| {
+            !before.iter().any(|old| {
+                old.location == entry.location
+                    && old.source == entry.source
+                    && old.key == entry.key
+                    && old.table == entry.table
+            })
+        })
+        .collect::<Vec<_>>();
+    for entry in written {
+        if let Err(mes) = record_provenance(cargo_path, &entry, &provenance, comment) {
+            error_log!(code = "provenance-failed", "{}", mes);
+            return;
         }
-        _ => {
-            let path_patch = PathPatch::new(args.real_package_name, args.patch_path.unwrap());
-            path_patch::do_path_patch(&cargo_path, &args.package_name, path_patch);
+    }
+}
+
+/// Apply a patch spec, and the same patch target for its family if `family` is set
+pub(crate) fn apply_patch(cargo_path: &String, mut spec: PatchSpec, family: bool) {
+    let package_name = spec.name.clone();
+    // Detect whether the dependency has been renamed if the real package name is not given
+    if spec.target.package().is_none() {
+        match resolve_package_name(cargo_path, &package_name) {
+            Ok(real_package_name) => {
+                if real_package_name != package_name {
+                    info_log!(
+                        "The dependency {} is renamed from the package {}",
+                        package_name,
+                        real_package_name
+                    );
+                    spec.target.set_package(Some(real_package_name));
+                }
+            }
+            Err(mes) => {
+                error_log!(code = "ambiguous-dependency", "{}", mes);
+                return;
+            }
+        }
+    }
+    let real_package_name = spec
+        .target
+        .package()
+        .cloned()
+        .unwrap_or(package_name.clone());
+    // The packages of the same family, except the given package itself
+    let family = if family {
+        match pick_family(cargo_path, &real_package_name) {
+            Ok(family) => family.into_iter().skip(1).collect(),
+            Err(mes) => {
+                error_log!(code = "family-failed", "{}", mes);
+                return;
+            }
+        }
+    } else {
+        Vec::new()
+    };
+    match spec.target {
+        PatchTarget::Git(git_patch) => {
+            let member_patch = git_patch.for_family_member();
+            git_patch::do_git_patch(cargo_path, &package_name, git_patch);
+            for member in family {
+                info_log!("Patch {} of the same family", member.name);
+                git_patch::do_git_patch(cargo_path, &member.name, member_patch.clone());
+            }
         }
+        PatchTarget::Index(index_patch) => {
+            index_patch::do_index_patch(cargo_path, &package_name, &index_patch);
+        }
+        PatchTarget::Path(path_patch) => {
+            if family.is_empty() {
+                path_patch::do_path_patch(cargo_path, &package_name, path_patch);
+                return;
+            }
+            // Patch every member of the family to the crate with the same name in the local path
+            let patch_path = path_patch.path();
+            let local_crates = path_patch::find_local_crates(cargo_path, patch_path);
+            let members = std::iter::once((package_name.clone(), real_package_name)).chain(
+                family
+                    .into_iter()
+                    .map(|member| (member.name.clone(), member.name)),
+            );
+            for (package_name, real_package_name) in members {
+                let Some((_, local_path)) = local_crates
+                    .iter()
+                    .find(|(name, _)| *name == real_package_name)
+                else {
+                    warn_log!(
+                        "The package {} is not found in {}, skip it",
+                        real_package_name,
+                        patch_path
+                    );
+                    continue;
+                };
+                let package = (package_name != real_package_name).then_some(real_package_name);
+                let path_patch = PathPatch::new(package, local_path.clone());
+                path_patch::do_path_patch(cargo_path, &package_name, path_patch);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::LockedPackage;
+
+    #[test]
+    fn version_key_for_the_compatible_ranges() {
+        assert_eq!(version_key("rand", "0.7.3").as_deref(), Some("rand07"));
+        assert_eq!(version_key("rand", "0.0.3").as_deref(), Some("rand003"));
+        assert_eq!(version_key("rand", "1.2.3").as_deref(), Some("rand1"));
+        assert_eq!(version_key("rand", "^0.8").as_deref(), Some("rand08"));
+        assert_eq!(version_key("rand", "not a version"), None);
+    }
+
+    #[test]
+    fn package_alias_for_the_version_keys() {
+        assert_eq!(package_alias("rand07", "rand").as_deref(), Some("rand"));
+        assert_eq!(package_alias("rand", "rand"), None);
+    }
+
+    #[test]
+    fn patched_spec_of_the_locked_version() {
+        let locked = |version: &str| LockedPackage {
+            version: Some(version.to_string()),
+            source: None,
+        };
+        let before = LockSnapshot::from([
+            ("rand".to_string(), vec![locked("0.7.3"), locked("0.8.5")]),
+            ("strsim".to_string(), vec![locked("0.11.1")]),
+        ]);
+        let entry = |key: &str, package: &str, table: &str| PatchEntry {
+            source: "crates-io".to_string(),
+            key: key.to_string(),
+            package: package.to_string(),
+            table: toml::from_str(table).unwrap(),
+            location: Default::default(),
+            provenance: None,
+        };
+        // The project doesn't exist, so the versions are only known from the patches
+        let cargo_path = std::env::temp_dir()
+            .join(format!("dependencies-patch-missing-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let spec = |entry| patched_spec(&cargo_path, &entry, &before);
+        let git = r#"git = "https://github.com/me/rand.git""#;
+
+        assert_eq!(
+            spec(entry("rand", "rand", r#"version = "0.8.1""#)),
+            "rand@0.8.5"
+        );
+        assert_eq!(spec(entry("rand07", "rand", git)), "rand@0.7.3");
+        assert_eq!(spec(entry("rand", "rand", git)), "rand");
+        assert_eq!(
+            spec(entry("strsim", "strsim", r#"path = "../strsim""#)),
+            "strsim@0.11.1"
+        );
+        assert_eq!(spec(entry("log", "log", r#"path = "../log""#)), "log");
     }
 }
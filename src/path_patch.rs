@@ -1,12 +1,18 @@
 //! Do patch as local path
 
-use std::{fs::OpenOptions, io::Write};
+use std::path::Path;
 
 use toml::Table;
 
-use crate::patch::gen_patch_table;
+use crate::{
+    config::config,
+    manifest::append_patch,
+    output::record_patch,
+    patch::{gen_patch_table, local_version, package_alias},
+};
 
 /// The information for path patch
+#[derive(Debug, Clone, PartialEq)]
 pub struct PathPatch {
     /// The real name of the package which may be renamed in the Cargo.toml
     package: Option<String>,
@@ -19,6 +25,20 @@ impl PathPatch {
     pub fn new(package: Option<String>, path: String) -> Self {
         Self { package, path }
     }
+    /// The target path of the patch
+    pub(crate) fn path(&self) -> &String {
+        &self.path
+    }
+
+    /// The real name of the package, if it is renamed
+    pub(crate) fn package(&self) -> Option<&String> {
+        self.package.as_ref()
+    }
+
+    /// Set the real name of the package, if it is renamed
+    pub(crate) fn set_package(&mut self, package: Option<String>) {
+        self.package = package;
+    }
 }
 
 /// Patch the specific package to the git repository
@@ -38,12 +58,17 @@ pub(crate) fn do_path_patch(cargo_path: &String, package_name: &String, patch: P
     };
 
     // The table which contains the patch information, will be written to the Cargo.toml
-    let (mut toml_table, package_index) =
-        if let Some(res) = gen_patch_table(cargo_path, package_name, real_package_name) {
-            res
-        } else {
-            return;
-        };
+    let version = local_version(cargo_path, &patch.path);
+    let (mut toml_table, package_index, key) = if let Some(res) = gen_patch_table(
+        cargo_path,
+        package_name,
+        real_package_name,
+        version.as_deref(),
+    ) {
+        res
+    } else {
+        return;
+    };
 
     let patch_toml_table = toml_table.get_mut("patch").unwrap().as_table_mut().unwrap();
     let index_table = patch_toml_table
@@ -56,15 +81,73 @@ pub(crate) fn do_path_patch(cargo_path: &String, package_name: &String, patch: P
     let mut patch_table = Table::new();
 
     patch_table.insert("path".to_string(), toml::Value::String(patch.path.clone()));
+    // The key differs from the real package name for a renamed package or another version
+    if let Some(target_package) = patch
+        .package
+        .clone()
+        .or(package_alias(&key, real_package_name))
+    {
+        patch_table.insert("package".to_string(), toml::Value::String(target_package));
+    }
 
-    index_table.insert(package_name.clone(), toml::Value::Table(patch_table));
-    // Write the patch table to the Cargo.toml in appending mode
-    let mut file = OpenOptions::new()
-        .append(true)
-        .open(format!("{}/Cargo.toml", cargo_path))
-        .unwrap();
-    file.write_all("\n".as_bytes()).unwrap();
-    if let Err(mes) = file.write_all(toml::to_string(&toml_table).unwrap().as_bytes()) {
-        error_log!("{}", mes);
+    index_table.insert(key.clone(), toml::Value::Table(patch_table.clone()));
+    // Write the patch table in appending mode
+    if let Err(mes) = append_patch(cargo_path, config().patch_location(), &toml_table) {
+        error_log!(code = "write-failed", "{}", mes);
+        return;
     };
+    record_patch(&package_index, &key, &patch_table);
+}
+
+/// Find all the crates inside the given local path, such as the members of a local workspace
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project, which the relative local path is based on
+///
+/// - `path`: The local path to be searched
+///
+/// # Return
+///
+/// A list of `(crate name, crate path)`, where the crate path is the given path joined with
+/// the relative directory of the crate, so it can be written into the patch directly.
+pub(crate) fn find_local_crates(cargo_path: &String, path: &String) -> Vec<(String, String)> {
+    fn walk(dir: &Path, patch_path: String, crates: &mut Vec<(String, String)>) {
+        if let Ok(manifest) = std::fs::read_to_string(dir.join("Cargo.toml")) {
+            if let Some(name) = toml::from_str::<Table>(&manifest)
+                .ok()
+                .and_then(|manifest| {
+                    manifest
+                        .get("package")
+                        .and_then(|package| package.get("name"))
+                        .and_then(|name| name.as_str().map(String::from))
+                })
+            {
+                crates.push((name, patch_path.clone()));
+            }
+        }
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let mut entries = entries.flatten().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            // Skip the build outputs and the hidden directories such as `.git`
+            if file_name == "target" || file_name.starts_with('.') {
+                continue;
+            }
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                walk(
+                    &entry.path(),
+                    format!("{}/{}", patch_path.trim_end_matches('/'), file_name),
+                    crates,
+                );
+            }
+        }
+    }
+
+    let mut crates = Vec::new();
+    walk(&Path::new(cargo_path).join(path), path.clone(), &mut crates);
+    crates
 }
@@ -0,0 +1,203 @@
+//! To toggle the patch profiles defined in the project config
+//!
+//! The patches applied by `profile enable` record the profile in their provenance, so
+//! `profile disable` removes exactly them and never touches the patches added otherwise. For the
+//! same reason, `profile enable` never replaces a patch which isn't from a profile, even with
+//! `on-conflict = "replace"`.
+
+use serde::Serialize;
+
+use crate::{
+    config::{config, Profile},
+    logger::has_errors,
+    manifest::{read_patches, remove_patch, PatchEntry},
+    output::{is_json, record_data},
+    patch::apply_specs,
+    provenance::{remove_provenance, Provenance},
+    spec::parse_spec,
+};
+
+/// The state of a profile
+#[derive(Debug, Clone, Serialize)]
+struct ProfileState {
+    name: String,
+    /// The patch specs of the profile
+    patches: Vec<String>,
+    /// The keys of the patches applied from the profile, such as `crates-io.log`
+    enabled_patches: Vec<String>,
+}
+
+/// Get the profile by its name from the config
+fn find_profile(name: &str) -> Result<&'static Profile, String> {
+    config().profiles.get(name).ok_or(format!(
+        "The profile {} is not defined, the profiles are: {}",
+        name,
+        config()
+            .profiles
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(", ")
+    ))
+}
+
+/// Get the patches which are applied from the profile
+fn profile_entries(cargo_path: &String, name: &str) -> Result<Vec<PatchEntry>, String> {
+    Ok(read_patches(cargo_path)?
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .provenance
+                .as_ref()
+                .and_then(|provenance| provenance.profile.as_deref())
+                == Some(name)
+        })
+        .collect())
+}
+
+/// Apply all the patches of the profile, where any failure rolls back the whole profile
+pub(crate) fn enable(cargo_path: &String, name: &str) {
+    let profile = match find_profile(name) {
+        Ok(profile) => profile,
+        Err(mes) => {
+            error_log!(code = "profile-not-found", "{}", mes);
+            return;
+        }
+    };
+    let before = match read_patches(cargo_path) {
+        Ok(before) => before,
+        Err(mes) => {
+            error_log!(code = "manifest-failed", "{}", mes);
+            return;
+        }
+    };
+    match profile_entries(cargo_path, name) {
+        Ok(entries) if !entries.is_empty() => {
+            error_log!(
+                code = "profile-enabled",
+                "The profile {} is already enabled, disable it first to apply it again",
+                name
+            );
+            return;
+        }
+        Ok(_) => {}
+        Err(mes) => {
+            error_log!(code = "manifest-failed", "{}", mes);
+            return;
+        }
+    }
+    let mut specs = Vec::new();
+    for spec in &profile.patches {
+        match parse_spec(spec) {
+            Ok(spec) => specs.push(spec),
+            Err(err) => {
+                error_log!(code = "invalid-spec", "In the profile {}: {}", name, err);
+                return;
+            }
+        }
+    }
+    let provenance = Provenance {
+        profile: Some(name.to_string()),
+        ..Default::default()
+    };
+    apply_specs(cargo_path, specs, false, Some(provenance), false);
+    // The whole profile is rolled back if any patch fails
+    if has_errors() {
+        return;
+    }
+    if let Err(mes) = check_replaced(cargo_path, &before) {
+        error_log!(code = "patch-exists", "In the profile {}: {}", name, mes);
+        return;
+    }
+    info_log!("Enabled the profile {}", name);
+}
+
+/// Check that the patches which aren't from a profile are kept as they were
+fn check_replaced(cargo_path: &String, before: &[PatchEntry]) -> Result<(), String> {
+    let after = read_patches(cargo_path)?;
+    for old in before {
+        if old
+            .provenance
+            .as_ref()
+            .is_some_and(|provenance| provenance.profile.is_some())
+        {
+            continue;
+        }
+        let kept = after.iter().any(|entry| {
+            entry.location == old.location
+                && entry.source == old.source
+                && entry.key == old.key
+                && entry.table == old.table
+        });
+        if !kept {
+            return Err(format!(
+                "The patch {}.{} isn't from a profile and can't be replaced, remove it to enable the profile",
+                old.source, old.key
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Remove all the patches applied from the profile
+pub(crate) fn disable(cargo_path: &String, name: &str) {
+    let entries = match profile_entries(cargo_path, name) {
+        Ok(entries) => entries,
+        Err(mes) => {
+            error_log!(code = "manifest-failed", "{}", mes);
+            return;
+        }
+    };
+    if entries.is_empty() {
+        warn_log!("The profile {} is not enabled", name);
+        return;
+    }
+    for entry in &entries {
+        if let Err(mes) = remove_patch(cargo_path, entry.location, &entry.source, &entry.key)
+            .and_then(|_| remove_provenance(cargo_path, entry.location, &entry.source, &entry.key))
+        {
+            error_log!(code = "write-failed", "{}", mes);
+            return;
+        }
+        info_log!("Removed the patch {} for {}", entry.key, entry.source);
+    }
+    record_data(&entries);
+    info_log!("Disabled the profile {}", name);
+}
+
+/// List the profiles in the config and the patches applied from them
+pub(crate) fn list(cargo_path: &String) {
+    let mut states = Vec::new();
+    for (name, profile) in &config().profiles {
+        let enabled_patches = match profile_entries(cargo_path, name) {
+            Ok(entries) => entries
+                .iter()
+                .map(|entry| format!("{}.{}", entry.source, entry.key))
+                .collect(),
+            Err(mes) => {
+                error_log!(code = "manifest-failed", "{}", mes);
+                return;
+            }
+        };
+        states.push(ProfileState {
+            name: name.clone(),
+            patches: profile.patches.clone(),
+            enabled_patches,
+        });
+    }
+    if is_json() {
+        record_data(&states);
+        return;
+    }
+    if states.is_empty() {
+        info_log!("There is no profile in the config");
+        return;
+    }
+    for state in &states {
+        let enabled = match state.enabled_patches.is_empty() {
+            true => "disabled",
+            false => "enabled",
+        };
+        println!("{} ({}): {}", state.name, enabled, state.patches.join(", "));
+    }
+}
@@ -0,0 +1,453 @@
+//! To record why a patch exists
+//!
+//! The provenance of a patch is recorded in the file where the patch is. For a patch in the
+//! Cargo.toml, it is under `[workspace.metadata.dependencies-patch.patches.<source>.<key>]` for a
+//! workspace root, or `[package.metadata.dependencies-patch.patches.<source>.<key>]` otherwise:
+//!
+//! ```toml
+//! [workspace.metadata.dependencies-patch.patches.crates-io.ring]
+//! reason = "Waiting for the fix of the TLS handshake to be released"
+//! issue = "https://github.com/briansmith/ring/issues/1"
+//! expires = "2025-01-31"
+//! author = "Jane Doe <jane@example.com>"
+//! date = "2024-10-01"
+//! ```
+//!
+//! For a patch in the `.cargo/config.toml`, which has no metadata table, it is under
+//! `[dependencies-patch.patches.<source>.<key>]`, a table ignored by cargo, so the Cargo.toml
+//! stays untouched.
+
+use std::{fs, path::Path, process::Command};
+
+use serde::{Deserialize, Serialize};
+use toml::Table;
+use toml_edit::{DocumentMut, TableLike};
+
+use crate::{
+    manifest::{implicit_table, to_edit_table, PatchEntry, PatchLocation},
+    transaction::write_file,
+};
+
+/// The key of the tool in the metadata tables
+const METADATA_KEY: &str = "dependencies-patch";
+
+/// The provenance of a patch
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Provenance {
+    /// Why the patch exists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// The issue tracking the patch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issue: Option<String>,
+    /// The date after which the patch should be revisited, in the format of `YYYY-MM-DD`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    /// The profile which the patch belongs to, see `profile enable`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// The id of the shared patch file which the patch is synced from, see `sync`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub synced: Option<String>,
+    /// Who adds the patch, from the git config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// When the patch is added, in the format of `YYYY-MM-DD`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+}
+
+impl Provenance {
+    /// The lines describing the provenance, such as `reason: ...`
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (name, value) in [
+            ("reason", &self.reason),
+            ("issue", &self.issue),
+            ("expires", &self.expires),
+            ("profile", &self.profile),
+            ("synced from", &self.synced),
+        ] {
+            if let Some(value) = value {
+                lines.push(format!("{}: {}", name, value));
+            }
+        }
+        match (&self.author, &self.date) {
+            (Some(author), Some(date)) => lines.push(format!("added by {} on {}", author, date)),
+            (Some(author), None) => lines.push(format!("added by {}", author)),
+            (None, Some(date)) => lines.push(format!("added on {}", date)),
+            (None, None) => {}
+        }
+        lines
+    }
+}
+
+/// Check whether the date is in the format of `YYYY-MM-DD`
+pub(crate) fn check_date(date: &str) -> bool {
+    let parts = date.split('-').collect::<Vec<_>>();
+    let [year, month, day] = parts.as_slice() else {
+        return false;
+    };
+    let number = |part: &str, len: usize| {
+        (part.len() == len && part.chars().all(|ch| ch.is_ascii_digit()))
+            .then(|| part.parse::<u32>().unwrap())
+    };
+    let (Some(year), Some(month), Some(day)) = (number(year, 4), number(month, 2), number(day, 2))
+    else {
+        return false;
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+    (1..=days).contains(&day)
+}
+
+/// Get the author from the git config of the project, such as `Jane Doe <jane@example.com>`
+pub(crate) fn git_author(cargo_path: &String) -> Option<String> {
+    let git_config = |key: &str| {
+        let output = Command::new("git")
+            .args(["config", key])
+            .current_dir(cargo_path)
+            .output()
+            .ok()?;
+        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        (output.status.success() && !value.is_empty()).then_some(value)
+    };
+    match (git_config("user.name"), git_config("user.email")) {
+        (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+        (name, email) => name.or(email),
+    }
+}
+
+/// Read the file where the patches are as a document
+fn read_document(path: &String) -> Result<DocumentMut, String> {
+    let content =
+        fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    content
+        .parse::<DocumentMut>()
+        .map_err(|err| format!("Failed to parse {}: {}", path, err))
+}
+
+/// Get the paths of the tables where the provenances are recorded in the file, from the preferred
+fn provenance_tables(location: PatchLocation) -> Vec<Vec<&'static str>> {
+    match location {
+        PatchLocation::Manifest => ["workspace", "package"]
+            .map(|parent| vec![parent, "metadata", METADATA_KEY, "patches"])
+            .to_vec(),
+        PatchLocation::CargoConfig => vec![vec![METADATA_KEY, "patches"]],
+    }
+}
+
+/// Read the provenances of all the patches in the file
+///
+/// # Arguments
+///
+/// - `content`: The parsed Cargo.toml or `.cargo/config.toml`
+///
+/// - `location`: Which file the content is
+///
+/// # Return
+///
+/// - Ok(provenances): The source, the key and the provenance of each recorded patch
+/// - Err(mes): The error message
+pub(crate) fn read_provenances(
+    content: &Table,
+    location: PatchLocation,
+) -> Result<Vec<(String, String, Provenance)>, String> {
+    let mut provenances = Vec::new();
+    for path in provenance_tables(location) {
+        let mut table = Some(content);
+        for name in path {
+            table = table
+                .and_then(|table| table.get(name))
+                .and_then(|item| item.as_table());
+        }
+        let Some(patches) = table else {
+            continue;
+        };
+        for (source, source_table) in patches {
+            let Some(source_table) = source_table.as_table() else {
+                continue;
+            };
+            for (key, provenance) in source_table {
+                let provenance: Provenance = provenance.clone().try_into().map_err(|err| {
+                    format!(
+                        "Failed to parse the provenance of {}.{}: {}",
+                        source, key, err
+                    )
+                })?;
+                provenances.push((source.clone(), key.clone(), provenance));
+            }
+        }
+    }
+    Ok(provenances)
+}
+
+/// Record the provenance of the patch in the transaction
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project, where the Cargo.toml file is in
+///
+/// - `entry`: The patch which has been written, whose provenance is recorded in the same file
+///
+/// - `provenance`: The provenance to be recorded
+///
+/// - `comment`: Also write the provenance as a TOML comment above the patch
+pub(crate) fn record_provenance(
+    cargo_path: &String,
+    entry: &PatchEntry,
+    provenance: &Provenance,
+    comment: bool,
+) -> Result<(), String> {
+    let path = entry.location.path(cargo_path);
+    let mut document = read_document(&path)?;
+    // The metadata of the workspace is shared by all the members
+    let mut tables = provenance_tables(entry.location);
+    let table_path = match document.contains_table("workspace") {
+        true => tables.remove(0),
+        false => tables.pop().unwrap(),
+    };
+    let mut item = document.as_item_mut();
+    for name in table_path.into_iter().chain([entry.source.as_str()]) {
+        item = item
+            .as_table_like_mut()
+            .ok_or(format!("The {} in {} is not a table", name, path))?
+            .entry(name)
+            .or_insert(implicit_table());
+    }
+    item.as_table_like_mut()
+        .ok_or(format!(
+            "The metadata of the patches in {} is not a table",
+            path
+        ))?
+        .insert(
+            &entry.key,
+            toml_edit::Item::Table(to_edit_table(provenance)),
+        );
+    write_file(path.as_ref(), &document.to_string())?;
+
+    if comment {
+        write_comment(cargo_path, entry, provenance)?;
+    }
+    Ok(())
+}
+
+/// Write the provenance as a TOML comment above the patch
+fn write_comment(
+    cargo_path: &String,
+    entry: &PatchEntry,
+    provenance: &Provenance,
+) -> Result<(), String> {
+    let path = entry.location.path(cargo_path);
+    let content =
+        fs::read_to_string(&path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let mut document = content
+        .parse::<DocumentMut>()
+        .map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+    let table = document
+        .get_mut("patch")
+        .and_then(|patch| patch.get_mut(&entry.source))
+        .and_then(|source| source.get_mut(&entry.key))
+        .and_then(|table| table.as_table_mut())
+        .ok_or(format!(
+            "The patch {}.{} is not found in {}",
+            entry.source, entry.key, path
+        ))?;
+    let prefix = table
+        .decor()
+        .prefix()
+        .and_then(|prefix| prefix.as_str())
+        .unwrap_or_default()
+        .to_string();
+    let comment = provenance
+        .lines()
+        .iter()
+        .map(|line| format!("# {}\n", line))
+        .collect::<String>();
+    table.decor_mut().set_prefix(prefix + &comment);
+    write_file(path.as_ref(), &document.to_string())
+}
+
+/// Remove the table at the path, and the parent tables which become empty
+///
+/// # Return
+///
+/// Whether the table is found and removed
+fn remove_nested(table: &mut dyn TableLike, path: &[&str]) -> bool {
+    let [name, rest @ ..] = path else {
+        return false;
+    };
+    if rest.is_empty() {
+        return table.remove(name).is_some();
+    }
+    let Some(child) = table
+        .get_mut(name)
+        .and_then(|child| child.as_table_like_mut())
+    else {
+        return false;
+    };
+    let removed = remove_nested(child, rest);
+    if removed && child.is_empty() {
+        table.remove(name);
+    }
+    removed
+}
+
+/// Remove the provenance of the patch in the transaction, which does nothing if it isn't recorded
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project
+///
+/// - `location`: The file where the patch is
+///
+/// - `source`: The key of the source table
+///
+/// - `key`: The key of the package table
+pub(crate) fn remove_provenance(
+    cargo_path: &String,
+    location: PatchLocation,
+    source: &str,
+    key: &str,
+) -> Result<(), String> {
+    let path = location.path(cargo_path);
+    if !Path::new(&path).exists() {
+        return Ok(());
+    }
+    let mut document = read_document(&path)?;
+    let mut removed = false;
+    for mut table_path in provenance_tables(location) {
+        table_path.extend([source, key]);
+        removed |= match location {
+            // The `[workspace]` or `[package]` table itself is kept even if it becomes empty
+            PatchLocation::Manifest => document
+                .get_mut(table_path[0])
+                .and_then(|parent| parent.as_table_like_mut())
+                .is_some_and(|parent| remove_nested(parent, &table_path[1..])),
+            PatchLocation::CargoConfig => remove_nested(document.as_table_mut(), &table_path),
+        };
+    }
+    if !removed {
+        return Ok(());
+    }
+    write_file(path.as_ref(), &document.to_string())
+}
+
+/// Read the expiry date written as a TOML comment around the patch, such as `# expires: 2025-01-31`
+///
+/// The comments above the patch table, above the key of an inline patch and after its value
+/// are searched.
+pub(crate) fn comment_expiry(cargo_path: &String, entry: &PatchEntry) -> Option<String> {
+    let path = entry.location.path(cargo_path);
+    let document = fs::read_to_string(path).ok()?.parse::<DocumentMut>().ok()?;
+    let (key, item) = document
+        .get("patch")
+        .and_then(|patch| patch.get(&entry.source))
+        .and_then(|source| source.as_table_like())
+        .and_then(|source| source.get_key_value(&entry.key))?;
+    let raw = |decor: &toml_edit::Decor| {
+        [decor.prefix(), decor.suffix()]
+            .into_iter()
+            .flatten()
+            .filter_map(|raw| raw.as_str())
+            .collect::<String>()
+    };
+    let comments = match item {
+        toml_edit::Item::Table(table) => raw(table.decor()),
+        item => {
+            raw(key.leaf_decor())
+                + &item
+                    .as_value()
+                    .map(|value| raw(value.decor()))
+                    .unwrap_or_default()
+        }
+    };
+    comments.lines().find_map(|line| {
+        let (name, date) = line.trim().strip_prefix('#')?.split_once([':', '='])?;
+        let date = date.trim().trim_matches('"');
+        (name.trim() == "expires" && check_date(date)).then(|| date.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_date_validates_the_day_of_the_month() {
+        for date in [
+            "2025-01-31",
+            "2025-04-30",
+            "2025-02-28",
+            "2024-02-29",
+            "2000-02-29",
+        ] {
+            assert!(check_date(date), "{}", date);
+        }
+        for date in [
+            "2025-02-31",
+            "2025-04-31",
+            "2025-02-29",
+            "1900-02-29",
+            "2025-13-01",
+            "2025-00-10",
+            "2025-01-00",
+            "2025-1-01",
+            "25-01-01",
+            "2025/01/01",
+            "2025-01-01-01",
+        ] {
+            assert!(!check_date(date), "{}", date);
+        }
+    }
+
+    #[test]
+    fn record_provenance_next_to_the_patch() {
+        let dir = std::env::temp_dir().join(format!(
+            "dependencies-patch-provenance-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(dir.join(".cargo")).unwrap();
+        let cargo_toml = "[workspace]\nmembers = [\"app\"]\n";
+        fs::write(dir.join("Cargo.toml"), cargo_toml).unwrap();
+        fs::write(
+            dir.join(".cargo/config.toml"),
+            "[patch.crates-io]\nlog = { path = \"../log\" }\n",
+        )
+        .unwrap();
+        let cargo_path = dir.to_string_lossy().to_string();
+        let entry = PatchEntry {
+            source: "crates-io".to_string(),
+            key: "log".to_string(),
+            package: "log".to_string(),
+            table: Table::new(),
+            location: PatchLocation::CargoConfig,
+            provenance: None,
+        };
+        let provenance = Provenance {
+            reason: Some("fix the logger".to_string()),
+            ..Default::default()
+        };
+
+        record_provenance(&cargo_path, &entry, &provenance, false).unwrap();
+        let recorded = fs::read_to_string(dir.join(".cargo/config.toml")).unwrap();
+        let manifest = fs::read_to_string(dir.join("Cargo.toml")).unwrap();
+        remove_provenance(&cargo_path, entry.location, "crates-io", "log").unwrap();
+        let removed = fs::read_to_string(dir.join(".cargo/config.toml")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let recorded = toml::from_str::<Table>(&recorded).unwrap();
+        assert_eq!(
+            read_provenances(&recorded, PatchLocation::CargoConfig).unwrap(),
+            [("crates-io".to_string(), "log".to_string(), provenance)]
+        );
+        assert_eq!(manifest, cargo_toml);
+        assert_eq!(removed, "[patch.crates-io]\nlog = { path = \"../log\" }\n");
+    }
+}
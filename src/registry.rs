@@ -0,0 +1,143 @@
+//! To resolve the registries configured in the cargo config
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use toml::Table;
+
+use crate::cargo_parse::cargo_home;
+
+/// The name of the default registry
+pub(crate) const CRATES_IO: &str = "crates-io";
+
+/// The index urls of crates-io, as the git index and the sparse index
+const CRATES_IO_INDEXES: [&str; 2] = [
+    "https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+/// Get the key of the patch table for a registry source in the Cargo.lock
+///
+/// - crates-io, from either the git index or the sparse index, is `crates-io`
+/// - Other git indexes are their urls without the `registry+` prefix
+/// - Other sparse indexes are their urls with the `sparse+` prefix
+pub(crate) fn registry_source_key(source: &str) -> String {
+    let index = source.strip_prefix("registry+").unwrap_or(source);
+    if is_crates_io(index) {
+        CRATES_IO.to_string()
+    } else {
+        index.to_string()
+    }
+}
+
+/// Whether the index url is one of crates-io
+fn is_crates_io(index: &str) -> bool {
+    let index = index.trim_end_matches('/');
+    CRATES_IO_INDEXES
+        .iter()
+        .any(|known| known.trim_end_matches('/') == index)
+}
+
+/// Get the cargo config files which apply to the cargo project, from the highest priority
+///
+/// Cargo reads `.cargo/config.toml` in the project and all its parents, then in the cargo home.
+fn cargo_config_files(cargo_path: &String) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(project) = Path::new(cargo_path).canonicalize() {
+        dirs.extend(project.ancestors().map(|dir| dir.join(".cargo")));
+    }
+    if let Some(home) = cargo_home() {
+        if !dirs.contains(&home) {
+            dirs.push(home);
+        }
+    }
+    let mut files = Vec::new();
+    for dir in dirs {
+        // The `config` file without extension is the legacy name
+        for name in ["config.toml", "config"] {
+            let file = dir.join(name);
+            if file.is_file() {
+                files.push(file);
+                break;
+            }
+        }
+    }
+    files
+}
+
+/// Get the index url of the registry
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project, where the cargo config is looked up
+///
+/// - `registry`: The name of the registry in `[registries]` of the cargo config
+///
+/// # Return
+///
+/// - Ok(index): The index url of the registry, such as `sparse+https://my-mirror/index/`
+/// - Err(mes): The registry is not configured
+pub(crate) fn registry_index(cargo_path: &String, registry: &str) -> Result<String, String> {
+    if registry == CRATES_IO {
+        // crates-io is fetched from the git index if its protocol is `git`
+        return Ok(
+            match registry_config(cargo_path, registry, "protocol").as_deref() {
+                Some("git") => CRATES_IO_INDEXES[0].to_string(),
+                _ => CRATES_IO_INDEXES[1].to_string(),
+            },
+        );
+    }
+    registry_config(cargo_path, registry, "index").ok_or(format!(
+        "The registry {} is not found in [registries] of the cargo config, nor by {}",
+        registry,
+        registry_env(registry, "index")
+    ))
+}
+
+/// Get the name of the environment variable for a field of the registry,
+/// such as `CARGO_REGISTRIES_MY_MIRROR_INDEX`
+fn registry_env(registry: &str, key: &str) -> String {
+    format!(
+        "CARGO_REGISTRIES_{}_{}",
+        registry.to_uppercase().replace('-', "_"),
+        key.to_uppercase()
+    )
+}
+
+/// Get a field of the registry in `[registries]` of the cargo config, such as its `index`
+fn registry_config(cargo_path: &String, registry: &str, key: &str) -> Option<String> {
+    // The environment variable wins over the config files
+    if let Ok(value) = std::env::var(registry_env(registry, key)) {
+        return Some(value);
+    }
+    for file in cargo_config_files(cargo_path) {
+        let Ok(content) = fs::read_to_string(&file) else {
+            continue;
+        };
+        let config: Table = match toml::from_str(&content) {
+            Ok(config) => config,
+            Err(err) => {
+                warn_log!("Failed to parse {}: {}", file.display(), err);
+                continue;
+            }
+        };
+        let value = config
+            .get("registries")
+            .and_then(|registries| registries.get(registry))
+            .and_then(|registry| registry.get(key))
+            .and_then(|value| value.as_str());
+        if let Some(value) = value {
+            debug_log!(
+                "The {} of registry {} is resolved as {} from {}",
+                key,
+                registry,
+                value,
+                file.display()
+            );
+            return Some(value.to_string());
+        }
+    }
+    None
+}
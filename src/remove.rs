@@ -0,0 +1,79 @@
+//! Remove the patch of a package
+
+use crate::{
+    arg_parse::RemoveArgs,
+    manifest::{read_patches, remove_patch},
+    output::record_data,
+    provenance::remove_provenance,
+};
+
+/// Remove the patch of the package from the Cargo.toml
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project, where the Cargo.toml file is in
+///
+/// - `args`: The arguments of the `remove` command
+pub(crate) fn remove(cargo_path: &String, args: RemoveArgs) {
+    let entries = match read_patches(cargo_path) {
+        Ok(entries) => entries,
+        Err(mes) => {
+            error_log!(code = "manifest-failed", "{}", mes);
+            return;
+        }
+    };
+    let entries = entries
+        .into_iter()
+        .filter(|entry| {
+            args.source
+                .as_ref()
+                .is_none_or(|source| entry.source == *source)
+        })
+        .collect::<Vec<_>>();
+    // The patch can be specified by its key, such as `rand07` for one of the several versions,
+    // or by the real package name
+    let by_key = entries
+        .iter()
+        .filter(|entry| entry.key == args.package_name)
+        .cloned()
+        .collect::<Vec<_>>();
+    let entries = match by_key.is_empty() {
+        true => entries
+            .into_iter()
+            .filter(|entry| entry.package == args.package_name)
+            .collect(),
+        false => by_key,
+    };
+    let entry = match entries.as_slice() {
+        [] => {
+            error_log!(
+                code = "patch-not-found",
+                "The patch for package {} is not found!",
+                args.package_name
+            );
+            return;
+        }
+        [entry] => entry,
+        _ => {
+            error_log!(
+                code = "ambiguous-patch",
+                "The package {} is patched several times: {}. Please specify the key of the patch, or the source by `--source`",
+                args.package_name,
+                entries
+                    .iter()
+                    .map(|entry| format!("{} in {}", entry.key, entry.source))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            return;
+        }
+    };
+    if let Err(mes) = remove_patch(cargo_path, entry.location, &entry.source, &entry.key)
+        .and_then(|_| remove_provenance(cargo_path, entry.location, &entry.source, &entry.key))
+    {
+        error_log!(code = "write-failed", "{}", mes);
+        return;
+    }
+    record_data(entry);
+    info_log!("Removed the patch {} for {}", entry.key, entry.source);
+}
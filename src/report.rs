@@ -0,0 +1,400 @@
+//! To report the impact of a patch on the Cargo.lock file
+
+use std::{collections::BTreeMap, path::Path};
+
+use clap::ValueEnum;
+use semver::Version;
+use serde::Serialize;
+
+use crate::{
+    cargo_command::run_cargo,
+    cargo_parse::{load_resolve, read_cargo_lock, LockPackage},
+    transaction::track,
+};
+
+/// The format of the lockfile report
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ReportFormat {
+    /// Human readable lines
+    #[default]
+    Human,
+    /// A JSON document
+    Json,
+    /// A Markdown table, which is suitable for pasting into a pull request
+    Markdown,
+}
+
+/// A package locked in the Cargo.lock file
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub(crate) struct LockedPackage {
+    pub version: Option<String>,
+    pub source: Option<String>,
+}
+
+impl From<&LockPackage> for LockedPackage {
+    fn from(package: &LockPackage) -> Self {
+        Self {
+            version: package.version.clone(),
+            source: package.source.clone(),
+        }
+    }
+}
+
+/// The kind of the change of a package
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ChangeKind {
+    Added,
+    Removed,
+    Upgraded,
+    Downgraded,
+    /// The version is the same but it comes from another source
+    #[serde(rename = "re-sourced")]
+    Resourced,
+}
+
+impl ChangeKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Upgraded => "upgraded",
+            ChangeKind::Downgraded => "downgraded",
+            ChangeKind::Resourced => "re-sourced",
+        }
+    }
+}
+
+/// The change of a package in the Cargo.lock file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct LockChange {
+    pub kind: ChangeKind,
+    pub name: String,
+    pub before: Option<LockedPackage>,
+    pub after: Option<LockedPackage>,
+}
+
+/// The snapshot of the packages in the Cargo.lock file, grouped by their names
+pub(crate) type LockSnapshot = BTreeMap<String, Vec<LockedPackage>>;
+
+/// Take the snapshot of the Cargo.lock file, which is generated if it doesn't exist
+pub(crate) fn snapshot_lock(cargo_path: &String) -> Result<LockSnapshot, String> {
+    load_resolve(cargo_path)?;
+    let mut snapshot = LockSnapshot::new();
+    for package in read_cargo_lock(cargo_path)?.package.iter() {
+        snapshot
+            .entry(package.name.clone())
+            .or_default()
+            .push(package.into());
+    }
+    for packages in snapshot.values_mut() {
+        packages.sort();
+    }
+    Ok(snapshot)
+}
+
+/// Update the Cargo.lock file after the patch is applied, and take the snapshot again
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project
+///
+/// - `packages`: The package id specs of the patched packages, such as `rand@0.7.3`, which are
+///   updated to the patched versions. The version tells the package apart when several versions
+///   of it are locked
+pub(crate) fn update_lock(
+    cargo_path: &String,
+    packages: &[String],
+) -> Result<LockSnapshot, String> {
+    // Only update the workspace members and the patched packages, so the other packages keep
+    // their locked versions unless the patch requires them to change
+    let mut args = vec!["update", "--workspace"];
+    for package in packages {
+        args.push("-p");
+        args.push(package);
+    }
+    // The Cargo.lock is restored with the manifests if the transaction fails
+    track(&Path::new(cargo_path).join("Cargo.lock"))?;
+    run_cargo(cargo_path, &args)?;
+    snapshot_lock(cargo_path)
+}
+
+/// Compare two versions, where the invalid versions are compared as strings
+fn compare_version(before: &Option<String>, after: &Option<String>) -> std::cmp::Ordering {
+    let parse = |version: &Option<String>| {
+        version
+            .as_ref()
+            .and_then(|version| Version::parse(version).ok())
+    };
+    match (parse(before), parse(after)) {
+        (Some(before), Some(after)) => before.cmp(&after),
+        _ => before.cmp(after),
+    }
+}
+
+/// Diff the snapshots of the Cargo.lock file before and after the patch
+pub(crate) fn diff_lock(before: &LockSnapshot, after: &LockSnapshot) -> Vec<LockChange> {
+    let mut changes = Vec::new();
+    let empty = Vec::new();
+    let names = before
+        .keys()
+        .chain(after.keys())
+        .collect::<std::collections::BTreeSet<_>>();
+    for name in names {
+        let before = before.get(name).unwrap_or(&empty);
+        let after = after.get(name).unwrap_or(&empty);
+        let mut removed = before
+            .iter()
+            .filter(|package| !after.contains(package))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut added = after
+            .iter()
+            .filter(|package| !before.contains(package))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // The same version from another source is re-sourced
+        removed.retain(|old| {
+            let Some(pos) = added.iter().position(|new| new.version == old.version) else {
+                return true;
+            };
+            let new = added.remove(pos);
+            changes.push(LockChange {
+                kind: ChangeKind::Resourced,
+                name: name.clone(),
+                before: Some(old.clone()),
+                after: Some(new),
+            });
+            false
+        });
+        // The other versions are paired in order as upgraded or downgraded
+        while !removed.is_empty() && !added.is_empty() {
+            let old = removed.remove(0);
+            let new = added.remove(0);
+            let kind = match compare_version(&old.version, &new.version) {
+                std::cmp::Ordering::Greater => ChangeKind::Downgraded,
+                _ => ChangeKind::Upgraded,
+            };
+            changes.push(LockChange {
+                kind,
+                name: name.clone(),
+                before: Some(old),
+                after: Some(new),
+            });
+        }
+        changes.extend(removed.into_iter().map(|old| LockChange {
+            kind: ChangeKind::Removed,
+            name: name.clone(),
+            before: Some(old),
+            after: None,
+        }));
+        changes.extend(added.into_iter().map(|new| LockChange {
+            kind: ChangeKind::Added,
+            name: name.clone(),
+            before: None,
+            after: Some(new),
+        }));
+    }
+    changes
+}
+
+/// Describe a locked package, such as `0.4.22 (registry+https://github.com/rust-lang/crates.io-index)`
+fn describe(package: &Option<LockedPackage>) -> String {
+    let Some(package) = package else {
+        return "-".to_string();
+    };
+    let version = package.version.clone().unwrap_or("?".to_string());
+    match &package.source {
+        Some(source) => format!("{} ({})", version, source),
+        None => version,
+    }
+}
+
+/// Render the lockfile report in the given format
+pub(crate) fn render_report(changes: &[LockChange], format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Human => {
+            if changes.is_empty() {
+                return "No package in Cargo.lock is changed".to_string();
+            }
+            changes
+                .iter()
+                .map(|change| {
+                    format!(
+                        "{:<10} {} {} -> {}",
+                        change.kind.as_str(),
+                        change.name,
+                        describe(&change.before),
+                        describe(&change.after)
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        ReportFormat::Json => serde_json::to_string_pretty(changes).unwrap(),
+        ReportFormat::Markdown => {
+            if changes.is_empty() {
+                return "No package in `Cargo.lock` is changed".to_string();
+            }
+            let mut report =
+                String::from("| Change | Package | Before | After |\n|---|---|---|---|\n");
+            for change in changes {
+                report.push_str(&format!(
+                    "| {} | `{}` | {} | {} |\n",
+                    change.kind.as_str(),
+                    change.name,
+                    describe(&change.before),
+                    describe(&change.after)
+                ));
+            }
+            report.trim_end().to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CRATES_IO: &str = "registry+https://github.com/rust-lang/crates.io-index";
+
+    fn locked(version: &str, source: Option<&str>) -> LockedPackage {
+        LockedPackage {
+            version: Some(version.to_string()),
+            source: source.map(str::to_string),
+        }
+    }
+
+    fn snapshot(packages: &[(&str, LockedPackage)]) -> LockSnapshot {
+        let mut snapshot = LockSnapshot::new();
+        for (name, package) in packages {
+            snapshot
+                .entry(name.to_string())
+                .or_default()
+                .push(package.clone());
+        }
+        for packages in snapshot.values_mut() {
+            packages.sort();
+        }
+        snapshot
+    }
+
+    fn change(
+        kind: ChangeKind,
+        name: &str,
+        before: Option<LockedPackage>,
+        after: Option<LockedPackage>,
+    ) -> LockChange {
+        LockChange {
+            kind,
+            name: name.to_string(),
+            before,
+            after,
+        }
+    }
+
+    #[test]
+    fn diff_lock_added_and_removed() {
+        let before = snapshot(&[("log", locked("0.4.22", Some(CRATES_IO)))]);
+        let after = snapshot(&[("cfg-if", locked("1.0.0", Some(CRATES_IO)))]);
+        assert_eq!(
+            diff_lock(&before, &after),
+            vec![
+                change(
+                    ChangeKind::Added,
+                    "cfg-if",
+                    None,
+                    Some(locked("1.0.0", Some(CRATES_IO)))
+                ),
+                change(
+                    ChangeKind::Removed,
+                    "log",
+                    Some(locked("0.4.22", Some(CRATES_IO))),
+                    None
+                ),
+            ]
+        );
+        assert!(diff_lock(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn diff_lock_resourced() {
+        let before = snapshot(&[("log", locked("0.4.22", Some(CRATES_IO)))]);
+        let after = snapshot(&[("log", locked("0.4.22", None))]);
+        assert_eq!(
+            diff_lock(&before, &after),
+            vec![change(
+                ChangeKind::Resourced,
+                "log",
+                Some(locked("0.4.22", Some(CRATES_IO))),
+                Some(locked("0.4.22", None))
+            )]
+        );
+    }
+
+    #[test]
+    fn diff_lock_upgraded_and_downgraded() {
+        let before = snapshot(&[
+            ("log", locked("0.4.9", Some(CRATES_IO))),
+            ("strsim", locked("0.11.1", Some(CRATES_IO))),
+        ]);
+        let after = snapshot(&[
+            ("log", locked("0.4.10", None)),
+            ("strsim", locked("0.10.0", None)),
+        ]);
+        assert_eq!(
+            diff_lock(&before, &after),
+            vec![
+                change(
+                    ChangeKind::Upgraded,
+                    "log",
+                    Some(locked("0.4.9", Some(CRATES_IO))),
+                    Some(locked("0.4.10", None))
+                ),
+                change(
+                    ChangeKind::Downgraded,
+                    "strsim",
+                    Some(locked("0.11.1", Some(CRATES_IO))),
+                    Some(locked("0.10.0", None))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_lock_pairs_the_changed_version_of_several() {
+        let before = snapshot(&[
+            ("rand", locked("0.7.3", Some(CRATES_IO))),
+            ("rand", locked("0.8.5", Some(CRATES_IO))),
+        ]);
+        let after = snapshot(&[
+            ("rand", locked("0.7.3", Some(CRATES_IO))),
+            ("rand", locked("0.8.6", None)),
+        ]);
+        assert_eq!(
+            diff_lock(&before, &after),
+            vec![change(
+                ChangeKind::Upgraded,
+                "rand",
+                Some(locked("0.8.5", Some(CRATES_IO))),
+                Some(locked("0.8.6", None))
+            )]
+        );
+
+        // The same version is re-sourced rather than paired with another version
+        let after = snapshot(&[
+            ("rand", locked("0.7.3", None)),
+            ("rand", locked("0.8.5", Some(CRATES_IO))),
+        ]);
+        assert_eq!(
+            diff_lock(&before, &after),
+            vec![change(
+                ChangeKind::Resourced,
+                "rand",
+                Some(locked("0.7.3", Some(CRATES_IO))),
+                Some(locked("0.7.3", None))
+            )]
+        );
+    }
+}
@@ -0,0 +1,539 @@
+//! To parse the compact patch specs
+//!
+//! A patch spec describes a patch in one argument:
+//!
+//! - `log=gh:rust-lang/log#tag=v0.4.22`: Patch to a github repository, where `gh` is a host
+//!   alias, and `gl` or the aliases in the config can be used too
+//!
+//! - `log=git+https://host/x.git#rev=abc`: Patch to a git url
+//!
+//! - `log=../log` or `log=path:../log`: Patch to a local path
+//!
+//! - `log@0.4.21#registry=mirror`: Patch to a version of another registry
+//!
+//! The fragment after `#` is a list of `key=value` separated by `&`, where the keys can be
+//! `rev`, `tag`, `branch`, `version` and `package` for git patch, or `registry` and `package`
+//! for registry patch.
+
+use std::fmt::Display;
+
+use semver::VersionReq;
+
+use crate::{
+    arg_parse::{AddArgs, PatchType},
+    config::config,
+    git_patch::{GitInfo, GitPatch},
+    index_patch::IndexPatch,
+    path_patch::PathPatch,
+};
+
+/// The target of a patch
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PatchTarget {
+    Git(GitPatch),
+    Path(PathPatch),
+    Index(IndexPatch),
+}
+
+impl PatchTarget {
+    /// The real name of the package, if it is renamed
+    pub fn package(&self) -> Option<&String> {
+        match self {
+            PatchTarget::Git(patch) => patch.package(),
+            PatchTarget::Path(patch) => patch.package(),
+            PatchTarget::Index(patch) => patch.package(),
+        }
+    }
+
+    /// Set the real name of the package, if it is renamed
+    pub fn set_package(&mut self, package: Option<String>) {
+        match self {
+            PatchTarget::Git(patch) => patch.set_package(package),
+            PatchTarget::Path(patch) => patch.set_package(package),
+            PatchTarget::Index(patch) => patch.set_package(package),
+        }
+    }
+}
+
+/// A patch for a package, which is given by the arguments or a patch spec
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PatchSpec {
+    /// The name of the package to be patched, which may be renamed
+    pub name: String,
+    pub target: PatchTarget,
+}
+
+impl PatchSpec {
+    /// Build the patch from the arguments of the `add` command
+    ///
+    /// The arguments should have been checked, so the package name and the patch type are given.
+    pub fn from_args(args: &AddArgs) -> Self {
+        let target = match args.patch_type.unwrap() {
+            PatchType::Git => {
+                let mut git_info = GitInfo::None;
+                if let Some(commit) = &args.commit {
+                    git_info = GitInfo::Commit(commit.to_string());
+                } else if let Some(tag) = &args.tag {
+                    git_info = GitInfo::Tag(tag.to_string());
+                } else if let Some(branch) = &args.branch {
+                    git_info = GitInfo::Branch(branch.to_string());
+                }
+                PatchTarget::Git(GitPatch::new(
+                    args.git_repo.clone().unwrap(),
+                    args.real_package_name.clone(),
+                    args.package_version.clone(),
+                    git_info,
+                ))
+            }
+            PatchType::Registry => PatchTarget::Index(IndexPatch::new(
+                args.real_package_name.clone(),
+                args.package_version.clone().unwrap_or("*".to_string()),
+                args.registry.clone(),
+            )),
+            PatchType::Path => PatchTarget::Path(PathPatch::new(
+                args.real_package_name.clone(),
+                args.patch_path.clone().unwrap(),
+            )),
+        };
+        Self {
+            name: args.package_name.clone().unwrap(),
+            target,
+        }
+    }
+}
+
+/// The error of parsing a patch spec, which points at the offending character
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SpecError {
+    pub spec: String,
+    /// The byte offset of the offending character
+    pub position: usize,
+    pub message: String,
+}
+
+impl Display for SpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let column = self.spec[..self.position.min(self.spec.len())]
+            .chars()
+            .count();
+        write!(
+            f,
+            "{} at column {}\n    {}\n    {}^",
+            self.message,
+            column + 1,
+            self.spec,
+            " ".repeat(column)
+        )
+    }
+}
+
+/// Build a parse error at the given position
+fn spec_error(spec: &str, position: usize, message: impl Into<String>) -> SpecError {
+    SpecError {
+        spec: spec.to_string(),
+        position,
+        message: message.into(),
+    }
+}
+
+/// The options given in the fragment after `#`
+#[derive(Default)]
+struct Fragment {
+    info: Option<GitInfo>,
+    version: Option<String>,
+    package: Option<String>,
+    registry: Option<String>,
+}
+
+/// The options allowed for the git patch
+const GIT_OPTIONS: [&str; 5] = ["rev", "tag", "branch", "version", "package"];
+
+/// The options allowed for the registry patch
+const REGISTRY_OPTIONS: [&str; 2] = ["registry", "package"];
+
+/// Parse the fragment, where `start` is its byte offset in the spec
+/// and `allowed` is the options allowed for the patch
+fn parse_fragment(spec: &str, start: usize, allowed: &[&str]) -> Result<Fragment, SpecError> {
+    let mut fragment = Fragment::default();
+    let mut offset = start;
+    for pair in spec[start..].split('&') {
+        let Some((key, value)) = pair.split_once('=') else {
+            return Err(spec_error(
+                spec,
+                offset + pair.len(),
+                "expected `=` after the option name",
+            ));
+        };
+        let value_offset = offset + key.len() + 1;
+        if value.is_empty() {
+            return Err(spec_error(spec, value_offset, "expected a value"));
+        }
+        if !allowed.contains(&key) {
+            let expected = allowed
+                .iter()
+                .map(|option| format!("`{}`", option))
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(spec_error(
+                spec,
+                offset,
+                format!("unknown option `{}`, expected one of {}", key, expected),
+            ));
+        }
+        let info = match key {
+            "rev" => Some(GitInfo::Commit(value.to_string())),
+            "tag" => Some(GitInfo::Tag(value.to_string())),
+            "branch" => Some(GitInfo::Branch(value.to_string())),
+            "version" => {
+                if let Err(err) = VersionReq::parse(value) {
+                    return Err(spec_error(
+                        spec,
+                        value_offset,
+                        format!("invalid version requirement: {}", err),
+                    ));
+                }
+                fragment.version = Some(value.to_string());
+                None
+            }
+            "package" => {
+                fragment.package = Some(value.to_string());
+                None
+            }
+            "registry" => {
+                fragment.registry = Some(value.to_string());
+                None
+            }
+            _ => unreachable!(),
+        };
+        if info.is_some() {
+            if fragment.info.is_some() {
+                return Err(spec_error(
+                    spec,
+                    offset,
+                    "the rev, tag and branch can't be used with each other",
+                ));
+            }
+            fragment.info = info;
+        }
+        offset += pair.len() + 1;
+    }
+    Ok(fragment)
+}
+
+/// Check the git repo name in the format of `owner/repo`, where `start` is its byte offset
+///
+/// The owner can be omitted if the `fork-owner` is configured.
+fn check_repo_name(spec: &str, start: usize, repo: &str) -> Result<(), SpecError> {
+    let mut slashes = 0;
+    for (index, ch) in repo.char_indices() {
+        match ch {
+            '/' => {
+                slashes += 1;
+                if slashes > 1 || index == 0 || index + 1 == repo.len() {
+                    return Err(spec_error(
+                        spec,
+                        start + index,
+                        "expected the repo name in the format of `owner/repo`",
+                    ));
+                }
+            }
+            ch if ch.is_ascii_alphanumeric() || "-_.".contains(ch) => {}
+            _ => {
+                return Err(spec_error(
+                    spec,
+                    start + index,
+                    "invalid character in the repo name",
+                ))
+            }
+        }
+    }
+    if repo.is_empty() || (slashes == 0 && config().fork_owner.is_none()) {
+        return Err(spec_error(
+            spec,
+            start + repo.len(),
+            "expected the repo name in the format of `owner/repo`",
+        ));
+    }
+    Ok(())
+}
+
+/// Parse a patch spec, such as `log=gh:rust-lang/log#tag=v0.4.22`
+pub(crate) fn parse_spec(spec: &str) -> Result<PatchSpec, SpecError> {
+    let name_end = spec
+        .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '-' || ch == '_'))
+        .unwrap_or(spec.len());
+    if name_end == 0 {
+        return Err(spec_error(spec, 0, "expected a package name"));
+    }
+    let name = spec[..name_end].to_string();
+    let target = match spec[name_end..].chars().next() {
+        Some('@') => {
+            let (version, fragment) = match spec[name_end + 1..].find('#') {
+                Some(pos) => (
+                    &spec[name_end + 1..name_end + 1 + pos],
+                    parse_fragment(spec, name_end + pos + 2, &REGISTRY_OPTIONS)?,
+                ),
+                None => (&spec[name_end + 1..], Fragment::default()),
+            };
+            if version.is_empty() {
+                return Err(spec_error(spec, name_end + 1, "expected a version"));
+            }
+            if let Err(err) = VersionReq::parse(version) {
+                return Err(spec_error(
+                    spec,
+                    name_end + 1,
+                    format!("invalid version requirement: {}", err),
+                ));
+            }
+            PatchTarget::Index(IndexPatch::new(
+                fragment.package,
+                version.to_string(),
+                fragment.registry,
+            ))
+        }
+        Some('=') => parse_target(spec, name_end + 1)?,
+        Some(_) => {
+            return Err(spec_error(
+                spec,
+                name_end,
+                "expected `=` or `@` after the package name",
+            ))
+        }
+        None => {
+            return Err(spec_error(
+                spec,
+                name_end,
+                "expected `=<target>` or `@<version>` after the package name",
+            ))
+        }
+    };
+    Ok(PatchSpec { name, target })
+}
+
+/// Parse the target after `=`, where `start` is its byte offset in the spec
+fn parse_target(spec: &str, start: usize) -> Result<PatchTarget, SpecError> {
+    let target = &spec[start..];
+    if target.is_empty() {
+        return Err(spec_error(spec, start, "expected the target of the patch"));
+    }
+    if let Some(path) = target.strip_prefix("path:") {
+        if path.is_empty() {
+            return Err(spec_error(spec, start + 5, "expected a path"));
+        }
+        return Ok(PatchTarget::Path(PathPatch::new(None, path.to_string())));
+    }
+    let alias_len = target
+        .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '-' || ch == '_'))
+        .unwrap_or(target.len());
+    let (scheme_len, is_url) = if target.starts_with("git+") {
+        (4, true)
+    } else if alias_len > 1 && target[alias_len..].starts_with(':') {
+        // A single letter before `:` is a drive letter of a local path
+        if config().host(&target[..alias_len]).is_none() {
+            return Err(spec_error(
+                spec,
+                start,
+                format!("unknown host alias `{}`", &target[..alias_len]),
+            ));
+        }
+        (alias_len + 1, false)
+    } else {
+        // Anything else is a local path
+        return Ok(PatchTarget::Path(PathPatch::new(None, target.to_string())));
+    };
+    let location_start = start + scheme_len;
+    let (location, fragment) = match spec[location_start..].find('#') {
+        Some(pos) => (
+            &spec[location_start..location_start + pos],
+            Some(parse_fragment(
+                spec,
+                location_start + pos + 1,
+                &GIT_OPTIONS,
+            )?),
+        ),
+        None => (&spec[location_start..], None),
+    };
+    if is_url {
+        if !location.contains("://") {
+            return Err(spec_error(
+                spec,
+                location_start,
+                "expected a git url such as `https://host/owner/repo.git`",
+            ));
+        }
+    } else {
+        check_repo_name(spec, location_start, location)?;
+    }
+    let fragment = fragment.unwrap_or_default();
+    // The host alias is kept, and expanded when the patch is written
+    let git = if is_url {
+        location.to_string()
+    } else {
+        target[..scheme_len].to_string() + location
+    };
+    Ok(PatchTarget::Git(GitPatch::new(
+        git,
+        fragment.package,
+        fragment.version,
+        fragment.info.unwrap_or(GitInfo::None),
+    )))
+}
+
+/// Read the patch specs from a batch file, one spec per line
+///
+/// The empty lines and the lines starting with `#` are skipped.
+pub(crate) fn read_batch(path: &str) -> Result<Vec<PatchSpec>, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let mut specs = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        specs.push(parse_spec(line).map_err(|err| format!("{}:{}: {}", path, index + 1, err))?);
+    }
+    Ok(specs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn git(git: &str, package: Option<&str>, version: Option<&str>, info: GitInfo) -> PatchTarget {
+        PatchTarget::Git(GitPatch::new(
+            git.to_string(),
+            package.map(str::to_string),
+            version.map(str::to_string),
+            info,
+        ))
+    }
+
+    fn target(spec: &str) -> PatchTarget {
+        let patch = parse_spec(spec).unwrap();
+        assert_eq!(patch.name, "log");
+        patch.target
+    }
+
+    /// The 1-based column of the error, as it is shown to the user
+    fn error_column(spec: &str) -> (usize, String) {
+        let err = parse_spec(spec).err().unwrap();
+        let column = err.to_string().split(" at column ").nth(1).unwrap()[..]
+            .split('\n')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        (column, err.message)
+    }
+
+    #[test]
+    fn parse_git_host_alias() {
+        assert_eq!(
+            target("log=gh:rust-lang/log#tag=v0.4.22"),
+            git(
+                "gh:rust-lang/log",
+                None,
+                None,
+                GitInfo::Tag("v0.4.22".to_string())
+            )
+        );
+        assert_eq!(
+            target("log=gl:owner/log#branch=fix&package=log2&version=0.4"),
+            git(
+                "gl:owner/log",
+                Some("log2"),
+                Some("0.4"),
+                GitInfo::Branch("fix".to_string())
+            )
+        );
+        assert_eq!(
+            target("log=gh:rust-lang/log"),
+            git("gh:rust-lang/log", None, None, GitInfo::None)
+        );
+    }
+
+    #[test]
+    fn parse_git_url() {
+        assert_eq!(
+            target("log=git+https://host/x.git#rev=abc"),
+            git(
+                "https://host/x.git",
+                None,
+                None,
+                GitInfo::Commit("abc".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn parse_path() {
+        let path = |path: &str| PatchTarget::Path(PathPatch::new(None, path.to_string()));
+        assert_eq!(target("log=../log"), path("../log"));
+        assert_eq!(target("log=path:../log"), path("../log"));
+        assert_eq!(target("log=C:/src/log"), path("C:/src/log"));
+    }
+
+    #[test]
+    fn parse_registry() {
+        assert_eq!(
+            target("log@0.4.21#registry=mirror&package=log2"),
+            PatchTarget::Index(IndexPatch::new(
+                Some("log2".to_string()),
+                "0.4.21".to_string(),
+                Some("mirror".to_string())
+            ))
+        );
+        assert_eq!(
+            target("log@0.4"),
+            PatchTarget::Index(IndexPatch::new(None, "0.4".to_string(), None))
+        );
+    }
+
+    #[test]
+    fn error_columns() {
+        let cases = [
+            ("=../log", 1, "expected a package name"),
+            ("log", 4, "expected `=<target>` or `@<version>` after the package name"),
+            ("log!../log", 4, "expected `=` or `@` after the package name"),
+            ("log=", 5, "expected the target of the patch"),
+            ("log=path:", 10, "expected a path"),
+            ("log@", 5, "expected a version"),
+            ("log@#registry=mirror", 5, "expected a version"),
+            ("log=xx:owner/log", 5, "unknown host alias `xx`"),
+            ("log=git+host/x.git", 9, "expected a git url such as `https://host/owner/repo.git`"),
+            ("log=gh:rust-lang/log/x", 21, "expected the repo name in the format of `owner/repo`"),
+            ("log=gh:/log", 8, "expected the repo name in the format of `owner/repo`"),
+            ("log=gh:rust lang/log", 12, "invalid character in the repo name"),
+            ("log=gh:log", 11, "expected the repo name in the format of `owner/repo`"),
+            ("log=gh:a/log#tag", 17, "expected `=` after the option name"),
+            ("log=gh:a/log#tag=", 18, "expected a value"),
+            ("log=gh:a/log#tag=v1&rev=abc", 21, "the rev, tag and branch can't be used with each other"),
+            ("log=gh:a/log#registry=x", 14, "unknown option `registry`, expected one of `rev`, `tag`, `branch`, `version`, `package`"),
+            ("log@1#tag=v1", 7, "unknown option `tag`, expected one of `registry`, `package`"),
+        ];
+        for (spec, column, message) in cases {
+            assert_eq!(
+                error_column(spec),
+                (column, message.to_string()),
+                "{}",
+                spec
+            );
+        }
+        let (column, message) = error_column("log@x1");
+        assert_eq!(column, 5);
+        assert!(message.starts_with("invalid version requirement"));
+        let (column, message) = error_column("log=gh:a/log#version=^^1");
+        assert_eq!(column, 22);
+        assert!(message.starts_with("invalid version requirement"));
+    }
+
+    #[test]
+    fn error_column_counts_characters() {
+        let err = parse_spec("log=gh:é/lög/x").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid character in the repo name at column 8\n    log=gh:é/lög/x\n           ^"
+        );
+    }
+}
@@ -0,0 +1,399 @@
+//! To sync the patches from a shared patch file, such as the `team-patches.toml` of a team
+//!
+//! The shared file has the `[patch.<source>.<key>]` tables as in the Cargo.toml. Only the
+//! patches of the packages in the Cargo.lock of the project are synced. The synced patches record
+//! the id of the shared file in their provenance, which is given by `--id` or is the path of the
+//! file relative to the project, so the later syncs from the same file update or remove exactly
+//! them and never touch the patches added otherwise or synced from another file. The relative local paths in the shared file are relative to the
+//! file itself, and written relative to the project.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use serde::Serialize;
+use toml::Table;
+
+use crate::{
+    arg_parse::SyncArgs,
+    cargo_parse::{read_cargo_lock, workspace_manifests},
+    config::config,
+    manifest::{
+        describe_target, read_patch_set, read_patches, remove_patch, set_patch, PatchEntry,
+    },
+    monorepo::relative_path,
+    output::{is_json, record_data},
+    provenance::{git_author, record_provenance, remove_provenance, Provenance},
+    transaction::utc_now,
+};
+
+/// A difference between the patches of the project and the shared file
+#[derive(Debug, Clone, Serialize)]
+struct SyncChange {
+    /// `add`, `update`, `remove`, or `conflict` for a patch added to the project otherwise
+    action: &'static str,
+    source: String,
+    key: String,
+    /// The patch in the project
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current: Option<PatchEntry>,
+    /// The patch in the shared file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shared: Option<Table>,
+}
+
+impl SyncChange {
+    /// Describe the change, such as `update crates-io.log: ../log -> ../../log`
+    fn describe(&self) -> String {
+        let current = self.current.as_ref().map(|entry| entry.target());
+        let shared = self.shared.as_ref().map(describe_target);
+        let detail = match (current, shared) {
+            (Some(current), Some(shared)) => format!("{} -> {}", current, shared),
+            (current, shared) => current.or(shared).unwrap_or_default(),
+        };
+        format!("{} {}.{}: {}", self.action, self.source, self.key, detail)
+    }
+}
+
+/// Get the names of the packages in the Cargo.lock, except the members of the project
+fn used_packages(cargo_path: &String) -> Result<Vec<String>, String> {
+    let members = workspace_manifests(cargo_path)?
+        .into_iter()
+        .filter_map(|(_, manifest)| {
+            let name = manifest.get("package")?.get("name")?.as_str()?;
+            Some(name.to_string())
+        })
+        .collect::<Vec<_>>();
+    Ok(read_cargo_lock(cargo_path)?
+        .package
+        .into_iter()
+        .map(|locked| locked.name)
+        .filter(|name| !members.contains(name))
+        .collect())
+}
+
+/// Get the absolute path, which is canonical if the path exists
+fn absolute(path: &Path) -> Result<PathBuf, String> {
+    fs::canonicalize(path)
+        .or(std::path::absolute(path))
+        .map_err(|err| format!("Failed to resolve {}: {}", path.display(), err))
+}
+
+/// Get the root of the git repository of the project, or the project itself outside git
+fn repository_root(cargo_path: &String) -> Result<PathBuf, String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--show-toplevel"])
+        .current_dir(cargo_path)
+        .output()
+        .ok()
+        .filter(|output| output.status.success());
+    match output {
+        Some(output) => absolute(Path::new(
+            String::from_utf8_lossy(&output.stdout).trim_end(),
+        )),
+        None => absolute(Path::new(cargo_path)),
+    }
+}
+
+/// Get the identifier of the shared file, which is recorded by the synced patches
+///
+/// It is the id given by `--id`, or the path of the shared file relative to the project if the
+/// file is in the repository of the project, so it stays the same on every checkout. A shared
+/// file outside the repository must be given an id, since its relative path depends on where
+/// the checkouts are.
+fn shared_file_id(cargo_path: &String, file: &str, id: Option<&str>) -> Result<String, String> {
+    if let Some(id) = id {
+        return Ok(id.to_string());
+    }
+    let path = absolute(Path::new(file))?;
+    if !path.starts_with(repository_root(cargo_path)?) {
+        return Err(format!(
+            "The shared file {} is outside the repository of the project, give it a stable id by `--id`",
+            file
+        ));
+    }
+    let path = relative_path(&absolute(Path::new(cargo_path))?, &path);
+    Ok(path.to_string_lossy().to_string())
+}
+
+/// Rebase the relative local path of the patch from the shared file onto the project
+fn rebase_path(cargo_path: &String, file: &str, table: &mut Table) -> Result<(), String> {
+    let Some(path) = table.get("path").and_then(|path| path.as_str()) else {
+        return Ok(());
+    };
+    if Path::new(path).is_absolute() {
+        return Ok(());
+    }
+    let base = Path::new(file).parent().unwrap_or(Path::new(""));
+    let path = relative_path(
+        &absolute(Path::new(cargo_path))?,
+        &absolute(&base.join(path))?,
+    );
+    table.insert(
+        "path".to_string(),
+        toml::Value::String(path.to_string_lossy().to_string()),
+    );
+    Ok(())
+}
+
+/// Compare the patches of the project with the shared file
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project, where the Cargo.toml file is in
+///
+/// - `file`: The shared patch file
+///
+/// - `file_id`: The identifier of the shared file, see [`shared_file_id`]
+///
+/// # Return
+///
+/// - Ok(changes): The changes to make the project match the shared file
+/// - Err(mes): The error message
+fn diff_patches(cargo_path: &String, file: &str, file_id: &str) -> Result<Vec<SyncChange>, String> {
+    let shared = read_patch_set(&file.to_string())?;
+    let entries = read_patches(cargo_path)?;
+    let used = used_packages(cargo_path)?;
+    // Only the patches synced from this file are managed by it
+    let is_synced = |entry: &PatchEntry| {
+        entry
+            .provenance
+            .as_ref()
+            .is_some_and(|provenance| provenance.synced.as_deref() == Some(file_id))
+    };
+
+    let mut changes = Vec::new();
+    let mut wanted = Vec::new();
+    for (source, key, mut table) in shared {
+        let package = table
+            .get("package")
+            .and_then(|package| package.as_str())
+            .unwrap_or(&key);
+        if !used.iter().any(|used| used == package) {
+            debug_log!("Skip the patch {}.{} for the unused package", source, key);
+            continue;
+        }
+        rebase_path(cargo_path, file, &mut table)?;
+        let current = entries
+            .iter()
+            .find(|entry| entry.source == source && entry.key == key);
+        let action = match current {
+            None => "add",
+            Some(entry) if entry.table == table => {
+                wanted.push((source, key));
+                continue;
+            }
+            Some(entry) if is_synced(entry) => "update",
+            Some(_) => "conflict",
+        };
+        changes.push(SyncChange {
+            action,
+            source: source.clone(),
+            key: key.clone(),
+            current: current.cloned(),
+            shared: Some(table),
+        });
+        wanted.push((source, key));
+    }
+    for entry in entries.into_iter().filter(is_synced) {
+        if !wanted.contains(&(entry.source.clone(), entry.key.clone())) {
+            changes.push(SyncChange {
+                action: "remove",
+                source: entry.source.clone(),
+                key: entry.key.clone(),
+                current: Some(entry),
+                shared: None,
+            });
+        }
+    }
+    Ok(changes)
+}
+
+/// Make the project match the shared file by the change in the transaction
+fn apply_change(
+    cargo_path: &String,
+    change: &SyncChange,
+    provenance: &Provenance,
+) -> Result<(), String> {
+    let Some(table) = &change.shared else {
+        let entry = change.current.as_ref().unwrap();
+        remove_patch(cargo_path, entry.location, &entry.source, &entry.key)?;
+        return remove_provenance(cargo_path, entry.location, &entry.source, &entry.key);
+    };
+    let location = match &change.current {
+        Some(entry) => entry.location,
+        None => config().patch_location(),
+    };
+    set_patch(cargo_path, location, &change.source, &change.key, table)?;
+    let entry = PatchEntry {
+        source: change.source.clone(),
+        key: change.key.clone(),
+        package: table
+            .get("package")
+            .and_then(|package| package.as_str())
+            .unwrap_or(&change.key)
+            .to_string(),
+        table: table.clone(),
+        location,
+        provenance: None,
+    };
+    record_provenance(cargo_path, &entry, provenance, false)
+}
+
+/// Sync the patches from the shared file, or only show the differences with `--check`
+pub(crate) fn sync(cargo_path: &String, args: SyncArgs) {
+    let file_id = match shared_file_id(cargo_path, &args.from, args.id.as_deref()) {
+        Ok(file_id) => file_id,
+        Err(mes) => {
+            error_log!(code = "sync-failed", "{}", mes);
+            return;
+        }
+    };
+    let changes = match diff_patches(cargo_path, &args.from, &file_id) {
+        Ok(changes) => changes,
+        Err(mes) => {
+            error_log!(code = "sync-failed", "{}", mes);
+            return;
+        }
+    };
+    if is_json() {
+        record_data(&changes);
+    }
+
+    if args.check {
+        if !is_json() {
+            for change in &changes {
+                println!("{}", change.describe());
+            }
+        }
+        if changes.is_empty() {
+            info_log!("The patches match {}", args.from);
+        } else {
+            error_log!(
+                code = "patch-drift",
+                "{} patches differ from {}",
+                changes.len(),
+                args.from
+            );
+        }
+        return;
+    }
+
+    let provenance = Provenance {
+        synced: Some(file_id),
+        author: git_author(cargo_path),
+        date: Some(utc_now()[..10].to_string()),
+        ..Default::default()
+    };
+    for change in &changes {
+        if change.action == "conflict" {
+            warn_log!(
+                "The patch {}.{} isn't synced from {}, remove it to sync it",
+                change.source,
+                change.key,
+                args.from
+            );
+            continue;
+        }
+        if let Err(mes) = apply_change(cargo_path, change, &provenance) {
+            error_log!(code = "write-failed", "{}", mes);
+            return;
+        }
+        info_log!("{}", change.describe());
+    }
+    if changes.is_empty() {
+        info_log!("The patches already match {}", args.from);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_patches_manages_the_patches_synced_from_the_file() {
+        let dir =
+            std::env::temp_dir().join(format!("dependencies-patch-sync-{}", std::process::id()));
+        for path in ["shared", "vendor/log", "vendor/rand"] {
+            fs::create_dir_all(dir.join(path)).unwrap();
+        }
+        let dir = fs::canonicalize(&dir).unwrap();
+        let synced = |key: &str, file_id: &str| {
+            format!(
+                "[package.metadata.dependencies-patch.patches.crates-io.{}]\nsynced = \"{}\"\n",
+                key, file_id
+            )
+        };
+        fs::write(
+            dir.join("Cargo.toml"),
+            [
+                "[package]\nname = \"app\"\nversion = \"0.1.0\"\n".to_string(),
+                synced("rand", "shared/team.toml"),
+                synced("serde", "shared/team.toml"),
+                synced("time", "shared/team.toml"),
+                synced("url", "shared/other.toml"),
+                "[patch.crates-io]\n".to_string(),
+                "rand = { path = \"vendor/rand\" }\n".to_string(),
+                "regex = { path = \"../regex\" }\n".to_string(),
+                "serde = { path = \"../serde\" }\n".to_string(),
+                "time = { path = \"../time\" }\n".to_string(),
+                "url = { path = \"../url\" }\n".to_string(),
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        let lock = ["app", "log", "rand", "regex", "serde", "time", "url"]
+            .map(|name| format!("[[package]]\nname = \"{}\"\nversion = \"1.0.0\"\n", name))
+            .join("\n");
+        fs::write(dir.join("Cargo.lock"), lock).unwrap();
+        let file = dir.join("shared/team.toml");
+        fs::write(
+            &file,
+            r#"[patch.crates-io]
+log = { path = "../vendor/log" }
+rand = { path = "../vendor/rand" }
+regex = { git = "https://github.com/rust-lang/regex.git" }
+serde = { git = "https://github.com/serde-rs/serde.git" }
+tokio = { path = "../vendor/tokio" }
+"#,
+        )
+        .unwrap();
+        let cargo_path = dir.to_string_lossy().to_string();
+        let file = file.to_string_lossy().to_string();
+        let file_id = shared_file_id(&cargo_path, &file, None);
+        let given_id = shared_file_id(&cargo_path, &file, Some("team"));
+        let outside_id = shared_file_id(&cargo_path, "/", None);
+        let changes = diff_patches(&cargo_path, &file, "shared/team.toml");
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(file_id.unwrap(), "shared/team.toml");
+        assert_eq!(given_id.unwrap(), "team");
+        assert!(outside_id.unwrap_err().contains("`--id`"));
+        // The unchanged rand, the unused tokio and the url synced from another file are skipped
+        let changes = changes
+            .unwrap()
+            .into_iter()
+            .map(|change| (change.action, change.key, change.shared))
+            .collect::<Vec<_>>();
+        let table = |content: &str| Some(toml::from_str::<Table>(content).unwrap());
+        assert_eq!(
+            changes,
+            [
+                ("add", "log".to_string(), table("path = \"vendor/log\"")),
+                (
+                    "conflict",
+                    "regex".to_string(),
+                    table("git = \"https://github.com/rust-lang/regex.git\"")
+                ),
+                (
+                    "update",
+                    "serde".to_string(),
+                    table("git = \"https://github.com/serde-rs/serde.git\"")
+                ),
+                ("remove", "time".to_string(), None),
+            ]
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! To write the manifests and the cargo configs transactionally
+//!
+//! All the files changed by a command are written in one transaction:
+//!
+//! - Every file is written to a temporary file first and renamed atomically
+//!
+//! - The original of every file is backed up in `.dependencies-patch/backups/<timestamp>`
+//!   until the transaction is committed
+//!
+//! - An advisory lock on `.dependencies-patch/lock` keeps concurrent invocations away
+//!
+//! - If any error is logged during the command, all the files are restored
+
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    logger::error_count,
+    output::{commit_patches, discard_patches},
+};
+
+/// The directory of the tool in the cargo project
+pub(crate) const STATE_DIR: &str = ".dependencies-patch";
+
+/// The number of the newest backups to keep
+const MAX_BACKUPS: usize = 20;
+
+/// The state of the running transaction
+struct Transaction {
+    /// The path of the cargo project
+    cargo_path: PathBuf,
+    /// The lock file, which is locked until the transaction ends
+    lock: File,
+    /// The original contents of the touched files, where `None` means the file didn't exist
+    originals: Vec<(PathBuf, Option<Vec<u8>>)>,
+    /// The directory of the backups of this transaction
+    backup_dir: PathBuf,
+}
+
+static TRANSACTION: Mutex<Option<Transaction>> = Mutex::new(None);
+
+/// Get the directory of the tool in the cargo project
+pub(crate) fn state_dir(cargo_path: &String) -> PathBuf {
+    Path::new(cargo_path).join(STATE_DIR)
+}
+
+/// Get the current UTC time in the format of `2024-01-31T08:00:00Z`
+pub(crate) fn utc_now() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // Convert the days since the epoch to the civil date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Write the file atomically by writing a temporary file and renaming it
+fn atomic_write(path: &Path, content: &[u8]) -> Result<(), String> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)
+        .map_err(|err| format!("Failed to create {}: {}", parent.display(), err))?;
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = parent.join(format!(".{}.{}.tmp", file_name, std::process::id()));
+    let write = || -> std::io::Result<()> {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    };
+    write().map_err(|err| {
+        let _ = fs::remove_file(&temp_path);
+        format!("Failed to write {}: {}", path.display(), err)
+    })
+}
+
+/// Start a transaction, which waits for the transaction of another invocation
+fn begin(cargo_path: &String) -> Result<(), String> {
+    let state_dir = state_dir(cargo_path);
+    if !state_dir.exists() {
+        fs::create_dir_all(&state_dir)
+            .map_err(|err| format!("Failed to create {}: {}", state_dir.display(), err))?;
+        // Keep the state of the tool out of git
+        let gitignore = state_dir.join(".gitignore");
+        atomic_write(&gitignore, b"*\n")?;
+    }
+    let lock_path = state_dir.join("lock");
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|err| format!("Failed to open {}: {}", lock_path.display(), err))?;
+    match lock.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            info_log!("Waiting for another invocation on {}", cargo_path);
+            lock.lock()
+                .map_err(|err| format!("Failed to lock {}: {}", lock_path.display(), err))?;
+        }
+        Err(TryLockError::Error(err)) => {
+            return Err(format!("Failed to lock {}: {}", lock_path.display(), err));
+        }
+    }
+    let timestamp = utc_now().replace(['-', ':'], "");
+    let backup_dir =
+        state_dir
+            .join("backups")
+            .join(format!("{}-{}", timestamp, std::process::id()));
+    *TRANSACTION.lock().unwrap() = Some(Transaction {
+        cargo_path: PathBuf::from(cargo_path),
+        lock,
+        originals: Vec::new(),
+        backup_dir,
+    });
+    Ok(())
+}
+
+/// Record the original of the file before it is changed, and back it up
+///
+/// The file can be changed by this tool or by cargo, such as the Cargo.lock.
+/// Nothing is recorded if no transaction is running.
+pub(crate) fn track(path: &Path) -> Result<(), String> {
+    let mut transaction = TRANSACTION.lock().unwrap();
+    let Some(transaction) = transaction.as_mut() else {
+        return Ok(());
+    };
+    if transaction
+        .originals
+        .iter()
+        .any(|(tracked, _)| tracked == path)
+    {
+        return Ok(());
+    }
+    let original = match path.exists() {
+        true => Some(
+            fs::read(path).map_err(|err| format!("Failed to read {}: {}", path.display(), err))?,
+        ),
+        false => None,
+    };
+    if let Some(original) = &original {
+        let relative = path
+            .strip_prefix(&transaction.cargo_path)
+            .unwrap_or(Path::new(path.file_name().unwrap_or_default()));
+        let backup_path = transaction.backup_dir.join(relative);
+        atomic_write(&backup_path, original)?;
+        debug_log!("Backed up {} to {}", path.display(), backup_path.display());
+    }
+    transaction.originals.push((path.to_path_buf(), original));
+    Ok(())
+}
+
+/// Write a TOML file in the transaction
+///
+/// The content is verified to be valid TOML before the file is replaced.
+pub(crate) fn write_file(path: &Path, content: &str) -> Result<(), String> {
+    if let Err(err) = content.parse::<toml::Table>() {
+        return Err(format!(
+            "The new content of {} is not valid TOML: {}",
+            path.display(),
+            err
+        ));
+    }
+    track(path)?;
+    atomic_write(path, content.as_bytes())
+}
+
+/// Write a file of the tool, such as the journal, in the transaction
+pub(crate) fn write_raw_file(path: &Path, content: &[u8]) -> Result<(), String> {
+    track(path)?;
+    atomic_write(path, content)
+}
+
+/// Restore all the touched files
+fn rollback(transaction: &Transaction) {
+    for (path, original) in transaction.originals.iter().rev() {
+        // Skip the files which are tracked but not changed
+        if fs::read(path).ok() == *original {
+            continue;
+        }
+        let result = match original {
+            Some(original) => atomic_write(path, original),
+            None => fs::remove_file(path)
+                .map_err(|err| format!("Failed to remove {}: {}", path.display(), err)),
+        };
+        match result {
+            Ok(()) => {
+                info_log!("Restored {}", path.display());
+            }
+            Err(mes) => {
+                error_log!(code = "rollback-failed", "{}", mes);
+            }
+        }
+    }
+}
+
+/// Restore all the files touched so far in the running transaction, such as after a temporary change
+pub(crate) fn restore_tracked() {
+    let mut transaction = TRANSACTION.lock().unwrap();
+    let Some(transaction) = transaction.as_mut() else {
+        return;
+    };
+    rollback(transaction);
+    transaction.originals.clear();
+}
+
+/// Remove the backups of the committed transaction
+fn remove_backups(transaction: &Transaction) {
+    if !transaction.backup_dir.exists() {
+        return;
+    }
+    if let Err(err) = fs::remove_dir_all(&transaction.backup_dir) {
+        warn_log!(
+            "Failed to remove the backup {}: {}",
+            transaction.backup_dir.display(),
+            err
+        );
+    }
+}
+
+/// Remove the oldest backups of the failed transactions beyond the limit
+fn prune_backups(transaction: &Transaction) {
+    let Some(backups_dir) = transaction.backup_dir.parent() else {
+        return;
+    };
+    let mut backups = fs::read_dir(backups_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    // The names start with the timestamps, so they are sorted by time
+    backups.sort();
+    let excess = backups.len().saturating_sub(MAX_BACKUPS);
+    for backup in &backups[..excess] {
+        if let Err(err) = fs::remove_dir_all(backup) {
+            warn_log!("Failed to remove the backup {}: {}", backup.display(), err);
+        }
+    }
+}
+
+/// Run the command in a transaction
+///
+/// If any error is logged by the command, all the files written by it are restored.
+pub(crate) fn with_transaction(cargo_path: &String, command: impl FnOnce()) {
+    let errors = error_count();
+    if let Err(mes) = begin(cargo_path) {
+        error_log!(code = "lock-failed", "{}", mes);
+        return;
+    }
+    command();
+    let transaction = TRANSACTION.lock().unwrap().take().unwrap();
+    let failed = error_count() > errors;
+    if failed && !transaction.originals.is_empty() {
+        warn_log!("Rolling back the changes because of the errors");
+        rollback(&transaction);
+        prune_backups(&transaction);
+    } else {
+        remove_backups(&transaction);
+    }
+    // The patches of a failed transaction are never written
+    match failed {
+        true => discard_patches(),
+        false => commit_patches(),
+    }
+    // Release the lock of the transaction explicitly
+    let _ = transaction.lock.unlock();
+}
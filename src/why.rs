@@ -0,0 +1,277 @@
+//! To show who depends on a package before patching it
+
+use std::path::Path;
+
+use semver::{Version, VersionReq};
+use serde::Serialize;
+use toml::Table;
+
+use crate::{
+    arg_parse::WhyArgs,
+    cargo_parse::{
+        load_resolve, member_requirements, resolve_package_name, CargoPackage, CargoResolve,
+    },
+    output::{is_json, record_data},
+};
+
+/// Get the version the proposed patch would provide
+///
+/// It is given by `--version`, or read from the Cargo.toml in `--patch-path`.
+fn proposed_version(cargo_path: &String, args: &WhyArgs) -> Result<Option<Version>, String> {
+    let version = match (&args.version, &args.patch_path) {
+        (Some(version), _) => version.clone(),
+        (None, Some(patch_path)) => {
+            let manifest_path = Path::new(cargo_path).join(patch_path).join("Cargo.toml");
+            let manifest = std::fs::read_to_string(&manifest_path)
+                .map_err(|err| format!("Failed to read {}: {}", manifest_path.display(), err))?;
+            let manifest: Table = toml::from_str(&manifest)
+                .map_err(|err| format!("Failed to parse {}: {}", manifest_path.display(), err))?;
+            manifest
+                .get("package")
+                .and_then(|package| package.get("version"))
+                .and_then(|version| version.as_str())
+                .ok_or(format!(
+                    "The version of the package is not found in {}",
+                    manifest_path.display()
+                ))?
+                .to_string()
+        }
+        (None, None) => return Ok(None),
+    };
+    Version::parse(&version)
+        .map(Some)
+        .map_err(|err| format!("{} is not a valid version: {}", version, err))
+}
+
+/// A package in the inverse dependency tree
+#[derive(Debug, Clone, Serialize)]
+struct WhyNode {
+    name: String,
+    version: Option<String>,
+    source: Option<String>,
+    workspace_member: bool,
+    /// The requirements on its child package in the tree
+    requirements: Vec<String>,
+    /// Whether any requirement is not satisfied by the proposed version
+    unsatisfied: bool,
+    /// Whether the package is shown before, so its dependents are not expanded again
+    repeated: bool,
+    dependents: Vec<WhyNode>,
+}
+
+impl WhyNode {
+    fn new(package: &CargoPackage) -> Self {
+        Self {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            source: package.source.clone(),
+            workspace_member: package.workspace_member,
+            requirements: Vec::new(),
+            unsatisfied: false,
+            repeated: false,
+            dependents: Vec::new(),
+        }
+    }
+
+    /// Describe the package, such as `log v0.4.22 (requires ^0.4)`
+    fn describe(&self) -> String {
+        let version = self.version.clone().unwrap_or("?".to_string());
+        let mut description = match &self.source {
+            _ if self.workspace_member => format!("{} v{} (workspace member)", self.name, version),
+            Some(source) if !source.starts_with("registry+") => {
+                format!("{} v{} ({})", self.name, version, source)
+            }
+            Some(_) => format!("{} v{}", self.name, version),
+            None => format!("{} v{} (path)", self.name, version),
+        };
+        if !self.requirements.is_empty() {
+            description.push_str(&format!(" (requires {})", self.requirements.join(", ")));
+        }
+        description
+    }
+
+    /// Count the requirements not satisfied by the proposed version in the tree
+    fn unsatisfied_count(&self) -> usize {
+        self.dependents
+            .iter()
+            .map(|dependent| dependent.unsatisfied_count())
+            .sum::<usize>()
+            + self.unsatisfied as usize
+    }
+
+    /// Print the dependents of the package as a tree
+    fn print_dependents(&self, prefix: &str, proposed: &Option<Version>) {
+        for (index, dependent) in self.dependents.iter().enumerate() {
+            let last = index + 1 == self.dependents.len();
+            println!(
+                "{}{}{}{}{}",
+                prefix,
+                if last { "└── " } else { "├── " },
+                dependent.describe(),
+                match proposed {
+                    Some(version) if dependent.unsatisfied =>
+                        format!(" [not satisfied by {}]", version),
+                    _ => String::new(),
+                },
+                if dependent.repeated { " (*)" } else { "" }
+            );
+            dependent.print_dependents(
+                &format!("{}{}", prefix, if last { "    " } else { "│   " }),
+                proposed,
+            );
+        }
+    }
+}
+
+/// Build the inverse dependency tree from the package up to the workspace members
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project, where the manifests of the workspace members are
+///
+/// - `proposed`: The version the proposed patch would provide, which is only checked against the
+///   requirements on the package itself
+///
+/// - `path`: The ids of the packages from the root to the package, to break the cycles
+///
+/// - `shown`: The ids of the packages which have been expanded
+fn build_dependents(
+    cargo_path: &String,
+    resolve: &CargoResolve,
+    package: &CargoPackage,
+    proposed: &Option<Version>,
+    path: &mut Vec<String>,
+    shown: &mut Vec<String>,
+) -> Vec<WhyNode> {
+    let mut nodes = Vec::new();
+    for dependent in resolve.dependents(&package.id) {
+        let mut node = WhyNode::new(dependent);
+        node.requirements = dependent
+            .requirements
+            .iter()
+            .filter(|requirement| requirement.name == package.name)
+            .map(|requirement| requirement.req.clone())
+            .collect();
+        node.requirements.dedup();
+        // The Cargo.lock has no requirements, but the workspace members declare theirs
+        if dependent.requirements.is_empty() && dependent.workspace_member {
+            node.requirements =
+                member_requirements(cargo_path, &dependent.name, &package.name).unwrap_or_default();
+        }
+        node.unsatisfied = proposed.as_ref().is_some_and(|version| {
+            node.requirements.iter().any(|req| {
+                VersionReq::parse(req)
+                    .map(|req| !req.matches(version))
+                    .unwrap_or(false)
+            })
+        });
+        // The packages shown before are not expanded again, and the cycles are broken
+        if shown.contains(&dependent.id) || path.contains(&dependent.id) {
+            node.repeated = !resolve.dependents(&dependent.id).is_empty();
+        } else {
+            shown.push(dependent.id.clone());
+            path.push(dependent.id.clone());
+            node.dependents = build_dependents(cargo_path, resolve, dependent, &None, path, shown);
+            path.pop();
+        }
+        nodes.push(node);
+    }
+    nodes
+}
+
+/// Show the inverse dependency paths from each workspace member to the package
+///
+/// # Arguments
+///
+/// - `cargo_path`: The path of the cargo project, where the Cargo.toml file is in
+///
+/// - `args`: The arguments of the `why` command
+pub(crate) fn why(cargo_path: &String, args: WhyArgs) {
+    let resolve = match load_resolve(cargo_path) {
+        Ok(resolve) => resolve,
+        Err(mes) => {
+            error_log!(code = "resolve-failed", "{}", mes);
+            return;
+        }
+    };
+    let proposed = match proposed_version(cargo_path, &args) {
+        Ok(version) => version,
+        Err(mes) => {
+            error_log!(code = "invalid-version", "{}", mes);
+            return;
+        }
+    };
+    // The name may be a renamed dependency
+    let name = match resolve_package_name(cargo_path, &args.name) {
+        Ok(name) => name,
+        Err(mes) => {
+            error_log!(code = "ambiguous-dependency", "{}", mes);
+            return;
+        }
+    };
+    let packages = resolve
+        .packages
+        .iter()
+        .filter(|package| package.name == name)
+        .collect::<Vec<_>>();
+    if packages.is_empty() {
+        error_log!(
+            code = "package-not-found",
+            "The package {} is not found in the Cargo.lock file",
+            name
+        );
+        return;
+    }
+    if resolve
+        .packages
+        .iter()
+        .all(|package| package.requirements.is_empty())
+    {
+        warn_log!("The version requirements of the packages other than the workspace members are only known with `--backend metadata`");
+    }
+
+    let trees = packages
+        .into_iter()
+        .map(|package| {
+            let mut root = WhyNode::new(package);
+            root.dependents = build_dependents(
+                cargo_path,
+                &resolve,
+                package,
+                &proposed,
+                &mut vec![package.id.clone()],
+                &mut Vec::new(),
+            );
+            root
+        })
+        .collect::<Vec<_>>();
+    let unsatisfied_count = trees
+        .iter()
+        .map(|tree| tree.unsatisfied_count())
+        .sum::<usize>();
+    if is_json() {
+        record_data(&serde_json::json!({
+            "package": name,
+            "proposed_version": proposed.as_ref().map(|version| version.to_string()),
+            "unsatisfied": unsatisfied_count,
+            "trees": trees,
+        }));
+    } else {
+        for tree in &trees {
+            println!("{}", tree.describe());
+            tree.print_dependents("", &proposed);
+        }
+    }
+    if let Some(version) = proposed {
+        if unsatisfied_count > 0 {
+            warn_log!(
+                "{} requirement(s) would not be satisfied by {} {}",
+                unsatisfied_count,
+                name,
+                version
+            );
+        } else {
+            info_log!("All the requirements are satisfied by {} {}", name, version);
+        }
+    }
+}
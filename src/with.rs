@@ -0,0 +1,76 @@
+//! To apply a patch set temporarily around a command
+//!
+//! The patch set has the `[patch.<source>.<key>]` tables as in the Cargo.toml:
+//!
+//! ```toml
+//! [patch.crates-io.log]
+//! path = "../log"
+//! ```
+//!
+//! The patches are written into the file given by the patch location, the command runs in the
+//! cargo project, and then the Cargo.toml, the `.cargo/config.toml` and the Cargo.lock are
+//! restored byte-for-byte by the transaction, even if the command fails or Ctrl-C is pressed.
+
+use std::path::Path;
+
+use toml::Table;
+
+use crate::{
+    arg_parse::WithArgs,
+    cargo_command::{defer_interrupts, is_interrupted, run_user_command},
+    config::config,
+    manifest::{read_patch_set, set_patch, PatchLocation},
+    transaction::{restore_tracked, track, with_transaction},
+};
+
+/// Write the patches and run the command, where all the files are tracked by the transaction
+fn apply_and_run(cargo_path: &String, patches: &[(String, String, Table)], command: &[String]) {
+    // The Cargo.lock is changed by the command rather than this tool
+    for path in [
+        PatchLocation::Manifest.path(cargo_path),
+        PatchLocation::CargoConfig.path(cargo_path),
+        format!("{}/Cargo.lock", cargo_path),
+    ] {
+        if let Err(mes) = track(Path::new(&path)) {
+            error_log!(code = "write-failed", "{}", mes);
+            return;
+        }
+    }
+    for (source, key, table) in patches {
+        if let Err(mes) = set_patch(cargo_path, config().patch_location(), source, key, table) {
+            error_log!(code = "write-failed", "{}", mes);
+            return;
+        }
+        info_log!("Patch {}.{} temporarily", source, key);
+    }
+    if is_interrupted() {
+        error_log!(
+            code = "interrupted",
+            "Interrupted before running the command"
+        );
+        return;
+    }
+    if let Err(mes) = run_user_command(cargo_path, command, &[]) {
+        error_log!(code = "exec-failed", "{}", mes);
+    }
+    if is_interrupted() {
+        error_log!(code = "interrupted", "The command is interrupted");
+    }
+}
+
+/// Apply the patch set, run the command and restore the files
+pub(crate) fn with(cargo_path: &String, args: WithArgs) {
+    let patches = match read_patch_set(&args.file) {
+        Ok(patches) => patches,
+        Err(mes) => {
+            error_log!(code = "invalid-patch-set", "{}", mes);
+            return;
+        }
+    };
+    // Keep running on Ctrl-C until the files are restored
+    defer_interrupts();
+    with_transaction(cargo_path, || {
+        apply_and_run(cargo_path, &patches, &args.command);
+        restore_tracked();
+    });
+}